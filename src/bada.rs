@@ -2,23 +2,87 @@ use std::fs;
 use std::env;
 use std::path::Path;
 use std::process::ExitCode;
+use std::io::{self, IsTerminal};
 
 #[macro_use]
 mod diag;
+mod builtins;
+mod callgraph;
+mod codes;
 mod compiler;
+mod desugar;
+mod fold;
+mod inline;
+mod ir;
 mod lex;
 mod parser;
+mod peephole;
+mod typecheck;
 
 use parser::Module;
 
+fn usage(program: &str) {
+    eprintln!("Usage: {program} [OPTIONS] <bada.boom>");
+    eprintln!("       {program} --explain <code>");
+    eprintln!("OPTIONS:");
+    eprintln!("    --no-type-guards    Do not check the types of the annotated parameters at run time");
+    eprintln!("    --print-callgraph   Print the call graph of the module in the DOT format instead of compiling it");
+    eprintln!("    --no-opt            Do not inline the small functions and do not run the peephole optimiser");
+    eprintln!("    --diagnostics-format=<human|json>");
+    eprintln!("                        Print the errors and the warnings for the people (default) or as JSON objects, one per line");
+    eprintln!("    --explain <code>    Print the explanation of the code of the error or the warning, like E0003");
+}
+
 fn main() -> ExitCode {
     let mut args = env::args();
     let program = args.next().expect("program");
 
-    let input_path = if let Some(input_path) = args.next() {
+    let mut options = compiler::Options::default();
+    let mut input_path = None;
+    let mut print_callgraph = false;
+    let mut diagnostics_format = diag::Format::Human;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--no-type-guards" => options.type_guards = false,
+            "--no-opt" => options.optimize = false,
+            "--print-callgraph" => print_callgraph = true,
+            "--diagnostics-format=human" => diagnostics_format = diag::Format::Human,
+            "--diagnostics-format=json" => diagnostics_format = diag::Format::Json,
+            "--explain" => {
+                let id = if let Some(id) = args.next() {
+                    id
+                } else {
+                    usage(&program);
+                    eprintln!("ERROR: no code is provided for --explain");
+                    return ExitCode::FAILURE;
+                };
+                if let Some(code) = codes::find(&id) {
+                    print!("{explanation}", explanation = code.explanation);
+                    return ExitCode::SUCCESS;
+                }
+                eprintln!("ERROR: unknown code {id}");
+                return ExitCode::FAILURE;
+            }
+            flag if flag.starts_with("--") => {
+                usage(&program);
+                eprintln!("ERROR: unknown flag {flag}");
+                return ExitCode::FAILURE;
+            }
+            _ => {
+                if input_path.is_some() {
+                    usage(&program);
+                    eprintln!("ERROR: only one input file is supported");
+                    return ExitCode::FAILURE;
+                }
+                input_path = Some(arg);
+            }
+        }
+    }
+
+    let input_path = if let Some(input_path) = input_path {
         input_path
     } else {
-        eprintln!("Usage: {program} <bada.boom>");
+        usage(&program);
         eprintln!("ERROR: no input is provided");
         return ExitCode::FAILURE;
     };
@@ -32,19 +96,35 @@ fn main() -> ExitCode {
             return ExitCode::FAILURE;
         }
     };
-    let mut lexer = lex::Lexer::new(&content, input_path.clone());
-    let module = if let Some(module) = Module::parse(&mut lexer) {
-        module
-    } else {
-        return ExitCode::FAILURE;
-    };
+    let bytes = compile(&content, &input_path, &options, print_callgraph);
 
-    let beam = compiler::compile_beam_module(&module);
-    let mut bytes: Vec<u8> = Vec::new();
-    bytes.extend("FOR1".as_bytes());
-    bytes.extend((beam.len() as u32).to_be_bytes());
-    bytes.extend(beam);
+    let diagnostics = diag::take_diagnostics();
+    match diagnostics_format {
+        diag::Format::Human => {
+            // https://no-color.org/
+            let colour = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+            diagnostics.print(&content, colour);
+        }
+        diag::Format::Json => diagnostics.print_json(),
+    }
+    let error_count = diagnostics.error_count();
+    if error_count > 0 {
+        // Nothing but the JSON objects goes to stderr for the tools
+        if let diag::Format::Human = diagnostics_format {
+            let errors = if error_count == 1 { "error" } else { "errors" };
+            eprintln!("ERROR: could not compile {input_path} due to {error_count} {errors}");
+            let first = diagnostics.reports.iter().find(|diagnostic| diagnostic.level == "ERROR").expect("at least one error");
+            eprintln!("INFO: For more information about an error, try `{program} --explain {id}`", id = first.code.id);
+        }
+        // The exit code is the number of errors, as much of it as fits
+        return ExitCode::from(error_count.min(u8::MAX as usize) as u8);
+    }
 
+    let bytes = match bytes {
+        Some(bytes) => bytes,
+        None if print_callgraph => return ExitCode::SUCCESS,
+        None => return ExitCode::FAILURE,
+    };
     if let Err(err) = fs::write(&output_path, &bytes) {
         eprintln!("ERROR: Could not write file {output_path}: {err}", output_path = output_path.display());
         return ExitCode::FAILURE;
@@ -53,4 +133,136 @@ fn main() -> ExitCode {
     ExitCode::SUCCESS
 }
 
+// The bytes of the .beam file. The diagnostics of all the stages are left
+// in the sink. Nothing is compiled when only the call graph is printed.
+fn compile(content: &[char], input_path: &str, options: &compiler::Options, print_callgraph: bool) -> Option<Vec<u8>> {
+    let mut lexer = lex::Lexer::new(content, input_path.to_string());
+    let mut module = Module::parse(&mut lexer)?;
+    desugar::desugar_module(&mut module);
+    typecheck::check_module(&module)?;
+    fold::fold_module(&mut module)?;
+
+    let graph = callgraph::CallGraph::build(&module);
+    if print_callgraph {
+        graph.print_dot(&module);
+        return None;
+    }
+    callgraph::remove_unreachable_funcs(&mut module, &graph);
+    inline::inline_module(&mut module, &graph, options);
+
+    let beam = compiler::compile_beam_module(&module, options)?;
+    let mut bytes: Vec<u8> = Vec::new();
+    bytes.extend("FOR1".as_bytes());
+    bytes.extend((beam.len() as u32).to_be_bytes());
+    bytes.extend(beam);
+    Some(bytes)
+}
+
 // TODO: implement BEAM disassembler as part of bada compiler
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The bytes of the module and the codes of all the diagnostics
+    fn compile_source(source: &str, options: &compiler::Options) -> (Option<Vec<u8>>, Vec<&'static str>) {
+        let content: Vec<char> = source.chars().collect();
+        let bytes = compile(&content, "test.boom", options, false);
+        let codes = diag::take_diagnostics().reports.iter().map(|diagnostic| diagnostic.code.id).collect();
+        (bytes, codes)
+    }
+
+    #[test]
+    fn function_reference_in_guard() {
+        let source = "\
+inc(x int) = x + 1;
+foo(f fun) when f == inc = 1;
+foo(f fun) = 0;
+";
+        let (bytes, codes) = compile_source(source, &compiler::Options::default());
+        assert!(bytes.is_none());
+        assert_eq!(codes, ["E0021"]);
+    }
+
+    #[test]
+    fn deterministic_output() {
+        let source = include_str!("../examples/bada.boom");
+        for options in [compiler::Options::default(), compiler::Options {type_guards: false, optimize: false}] {
+            let (first, _) = compile_source(source, &options);
+            let (second, _) = compile_source(source, &options);
+            assert!(first.is_some());
+            assert!(first == second);
+        }
+    }
+
+    #[test]
+    fn diagnostics_in_source_order() {
+        let source = "\
+zeta() = y;
+alpha() = z;
+beta(x int) = x + 'one';
+";
+        let content: Vec<char> = source.chars().collect();
+        compile(&content, "test.boom", &compiler::Options::default(), false);
+        let rows: Vec<usize> = diag::take_diagnostics().reports.iter().map(|diagnostic| diagnostic.span.start.row).collect();
+        assert_eq!(rows, [1, 2, 3]);
+    }
+
+    #[test]
+    fn unused_private_function() {
+        let source = "\
+private unused(x int) = x * 2;
+private helper(x int) = x + 1;
+main(x int) = helper(x);
+";
+        let (bytes, codes) = compile_source(source, &compiler::Options::default());
+        let bytes = bytes.expect("the warnings do not stop the compilation");
+        assert_eq!(codes, ["W0001"]);
+        assert!(!bytes.windows(b"unused".len()).any(|atom| atom == b"unused"));
+        assert!(bytes.windows(b"main".len()).any(|atom| atom == b"main"));
+    }
+
+    #[test]
+    fn partly_private_function() {
+        let source = "\
+private sign(x int) when x < 0 = 0 - 1;
+sign(x int) = 1;
+";
+        let (bytes, codes) = compile_source(source, &compiler::Options::default());
+        assert!(bytes.is_none());
+        assert_eq!(codes, ["E0033"]);
+    }
+
+    #[test]
+    fn distinct_codes() {
+        let cases = [
+            ("const LIMIT = 10;\nLIMIT() = 20;\n", "E0016"),
+            ("inc(x int) = x + 1;\ntwo() = inc(1, 2);\n", "E0017"),
+            ("type result = ok(float) | error(atom)\nok(x float) = {'ok', x};\n", "E0034"),
+            ("length(l list) = 0;\n", "E0035"),
+            ("record shape { width int }\ntype shape = circle(float)\n", "E0036"),
+            ("type result = ok(float)\nsuccess() = ok(1.0, 2.0);\n", "E0037"),
+            ("type result = ok(float)\nunwrap(r result) = case r of ok(x, y) -> x end;\n", "E0037"),
+            ("apply(f fn(int) -> int x int) = f(x, x);\n", "E0038"),
+            ("record point { x int }\norigin() = point{'x': 0};\n", "E0039"),
+        ];
+        for (source, code) in cases {
+            let (_, codes) = compile_source(source, &compiler::Options::default());
+            assert_eq!(codes, [code], "{}", source);
+        }
+    }
+
+    #[test]
+    fn json_ranges() {
+        let source = "\
+inc(x int) = x + foo;
+pair(x int) = {x, 'one'} + 1;
+";
+        let content: Vec<char> = source.chars().collect();
+        compile(&content, "test.boom", &compiler::Options::default(), false);
+        let lines = diag::take_diagnostics().json_lines();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"file\":\"test.boom\",\"line\":1,\"column\":18,\"end_line\":1,\"end_column\":21,"), "{}", lines[0]);
+        assert!(lines[1].starts_with("{\"file\":\"test.boom\",\"line\":2,\"column\":15,\"end_line\":2,\"end_column\":25,"), "{}", lines[1]);
+    }
+}
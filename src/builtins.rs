@@ -0,0 +1,226 @@
+use parser::Type;
+
+pub enum Lowering {
+    // bif0 Bif Dst, the BIF can't fail and does not touch the registers
+    Bif0,
+    // call_ext Arity Import, the arguments are passed in the x registers
+    CallExt,
+    // The send instruction
+    Send,
+    // bif1 Fail Bif Arg Dst, the BIF does not allocate. In guards it jumps to
+    // the next clause instead of raising.
+    Bif1,
+    // gc_bif1 Fail Live Bif Arg Dst, the same but the result may be allocated
+    // on the heap, so the BIF may trigger the GC
+    GcBif1,
+}
+
+pub struct Builtin {
+    pub name: &'static str,
+    // The types accepted by each parameter
+    pub params: &'static [&'static [Type]],
+    pub ret: Type,
+    pub module: &'static str,
+    pub func: &'static str,
+    pub lowering: Lowering,
+}
+
+impl Builtin {
+    pub fn arity(&self) -> usize {
+        self.params.len()
+    }
+
+    // Only the BIFs that can't have side effects are allowed in guards
+    pub fn is_guard_safe(&self) -> bool {
+        matches!(self.lowering, Lowering::Bif0 | Lowering::Bif1 | Lowering::GcBif1)
+    }
+}
+
+// The functions that are available in any Bada module. Both the type
+// checker and the code generator use this table, so they always agree.
+pub const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "self",
+        params: &[],
+        ret: Type::Pid,
+        module: "erlang",
+        func: "self",
+        lowering: Lowering::Bif0,
+    },
+    Builtin {
+        name: "spawn",
+        params: &[&[Type::Fun(None)]],
+        ret: Type::Pid,
+        module: "erlang",
+        func: "spawn",
+        lowering: Lowering::CallExt,
+    },
+    Builtin {
+        name: "spawn_link",
+        params: &[&[Type::Fun(None)]],
+        ret: Type::Pid,
+        module: "erlang",
+        func: "spawn_link",
+        lowering: Lowering::CallExt,
+    },
+    // The exceptions of the three classes. They never return.
+    Builtin {
+        name: "throw",
+        params: &[&[Type::Any]],
+        ret: Type::Any,
+        module: "erlang",
+        func: "throw",
+        lowering: Lowering::CallExt,
+    },
+    Builtin {
+        name: "error",
+        params: &[&[Type::Any]],
+        ret: Type::Any,
+        module: "erlang",
+        func: "error",
+        lowering: Lowering::CallExt,
+    },
+    Builtin {
+        name: "exit",
+        params: &[&[Type::Any]],
+        ret: Type::Any,
+        module: "erlang",
+        func: "exit",
+        lowering: Lowering::CallExt,
+    },
+    // Returns the message, just like `pid ! msg`
+    Builtin {
+        name: "send",
+        params: &[&[Type::Pid, Type::Atom], &[Type::Any]],
+        ret: Type::Any,
+        module: "erlang",
+        func: "send",
+        lowering: Lowering::Send,
+    },
+    // The type tests, mostly for the values of type any
+    Builtin {
+        name: "is_integer",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_integer",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_float",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_float",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_number",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_number",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_atom",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_atom",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_boolean",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_boolean",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_list",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_list",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_tuple",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_tuple",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_map",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_map",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_binary",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_binary",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_pid",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_pid",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "is_function",
+        params: &[&[Type::Any]],
+        ret: Type::Bool,
+        module: "erlang",
+        func: "is_function",
+        lowering: Lowering::Bif1,
+    },
+    // The sizes. The BEAM runs all of them but tuple_size as GC BIFs.
+    Builtin {
+        name: "length",
+        params: &[&[Type::List(None)]],
+        ret: Type::Int,
+        module: "erlang",
+        func: "length",
+        lowering: Lowering::GcBif1,
+    },
+    Builtin {
+        name: "tuple_size",
+        params: &[&[Type::Tuple(None)]],
+        ret: Type::Int,
+        module: "erlang",
+        func: "tuple_size",
+        lowering: Lowering::Bif1,
+    },
+    Builtin {
+        name: "map_size",
+        params: &[&[Type::Map(None)]],
+        ret: Type::Int,
+        module: "erlang",
+        func: "map_size",
+        lowering: Lowering::GcBif1,
+    },
+    Builtin {
+        name: "byte_size",
+        params: &[&[Type::Binary]],
+        ret: Type::Int,
+        module: "erlang",
+        func: "byte_size",
+        lowering: Lowering::GcBif1,
+    },
+];
+
+pub fn find(name: &str, arity: usize) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name && builtin.arity() == arity)
+}
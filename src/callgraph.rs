@@ -0,0 +1,154 @@
+use builtins;
+use parser::{Expr, ExprKind, Module, FuncClause, MatchClause, After};
+use std::collections::HashMap;
+
+// Which functions of the module each function calls or makes funs of. The
+// calls are resolved the same way the compiler does it: the constructors and
+// the built-in functions go first, and the variables shadow the functions.
+pub struct CallGraph {
+    // The callees are in the order of their first use
+    pub calls: HashMap<String, Vec<String>>,
+}
+
+fn collect_clause(MatchClause{pattern, guard, body}: &MatchClause, bound: &mut Vec<String>, module: &Module, calls: &mut Vec<String>) {
+    let bound_len = bound.len();
+    pattern.vars(bound);
+    if let Some(guard) = guard {
+        collect_calls(guard, bound, module, calls);
+    }
+    collect_calls(body, bound, module, calls);
+    bound.truncate(bound_len);
+}
+
+// `bound` are the variables that are in the scope of the expression
+fn collect_calls(expr: &Expr, bound: &mut Vec<String>, module: &Module, calls: &mut Vec<String>) {
+    let callee = match &expr.kind {
+        ExprKind::Var(name) if !bound.contains(&name.text) => Some(&name.text),
+        ExprKind::Call{name, args} => {
+            let is_local = module.find_constructor(&name.text).is_none()
+                && builtins::find(&name.text, args.len()).is_none()
+                && !bound.contains(&name.text);
+            if is_local { Some(&name.text) } else { None }
+        }
+        _ => None,
+    };
+    if let Some(callee) = callee {
+        if module.funcs.contains_key(callee) && !calls.contains(callee) {
+            calls.push(callee.clone());
+        }
+    }
+
+    match &expr.kind {
+        ExprKind::Case{subject, clauses} => {
+            collect_calls(subject, bound, module, calls);
+            for clause in clauses.iter() {
+                collect_clause(clause, bound, module, calls);
+            }
+        }
+        ExprKind::Receive{clauses, after} => {
+            for clause in clauses.iter() {
+                collect_clause(clause, bound, module, calls);
+            }
+            if let Some(After{timeout, body}) = after {
+                collect_calls(timeout, bound, module, calls);
+                collect_calls(body, bound, module, calls);
+            }
+        }
+        ExprKind::Try{body, clauses, catches} => {
+            collect_calls(body, bound, module, calls);
+            for clause in clauses.iter() {
+                collect_clause(clause, bound, module, calls);
+            }
+            for catch in catches.iter() {
+                let bound_len = bound.len();
+                catch.class.vars(bound);
+                collect_clause(&catch.clause, bound, module, calls);
+                bound.truncate(bound_len);
+            }
+        }
+        _ => {
+            for child in expr.children() {
+                collect_calls(child, bound, module, calls);
+            }
+        }
+    }
+}
+
+impl CallGraph {
+    pub fn build(module: &Module) -> Self {
+        let mut graph = Self {calls: HashMap::new()};
+        for (name, func) in module.funcs.iter() {
+            let mut calls = Vec::new();
+            for FuncClause{params, guard, body} in func.clauses.iter() {
+                let mut bound: Vec<String> = params.names().cloned().collect();
+                for param in params.iter() {
+                    if let Some(pattern) = &param.pattern {
+                        pattern.vars(&mut bound);
+                    }
+                }
+                if let Some(guard) = guard {
+                    collect_calls(guard, &mut bound, module, &mut calls);
+                }
+                collect_calls(body, &mut bound, module, &mut calls);
+            }
+            graph.calls.insert(name.clone(), calls);
+        }
+        graph
+    }
+
+    // The functions that can be called from the outside of the module,
+    // directly or through the exported functions
+    pub fn reachable(&self, module: &Module) -> Vec<String> {
+        let mut reachable: Vec<String> = module.funcs.iter()
+            .filter(|(_, func)| func.exported)
+            .map(|(name, _)| name.clone())
+            .collect();
+        let mut i = 0;
+        while i < reachable.len() {
+            for callee in self.calls[&reachable[i]].iter() {
+                if !reachable.contains(callee) {
+                    reachable.push(callee.clone());
+                }
+            }
+            i += 1;
+        }
+        reachable
+    }
+
+    // The graph in the format of Graphviz. The functions that are not
+    // exported are dashed.
+    pub fn print_dot(&self, module: &Module) {
+        let node = |name: &str| {
+            let arity = module.funcs[name].arity();
+            format!("\"{name}/{arity}\"", name = name.replace('\\', "\\\\").replace('"', "\\\""))
+        };
+        let names: Vec<&String> = module.funcs.keys().collect();
+        println!("digraph bada {{");
+        for name in names.iter() {
+            if module.funcs[*name].exported {
+                println!("    {node};", node = node(name));
+            } else {
+                println!("    {node} [style=dashed];", node = node(name));
+            }
+        }
+        for name in names.iter() {
+            for callee in self.calls[*name].iter() {
+                println!("    {caller} -> {callee};", caller = node(name), callee = node(callee));
+            }
+        }
+        println!("}}");
+    }
+}
+
+// The functions that nobody can call are left out of the module
+pub fn remove_unreachable_funcs(module: &mut Module, graph: &CallGraph) {
+    let reachable = graph.reachable(module);
+    let unreachable: Vec<String> = module.funcs.keys().filter(|name| !reachable.contains(name)).cloned().collect();
+    for name in unreachable.iter() {
+        let func = module.funcs.remove(name).expect("the function of the module");
+        // The helpers generated for the comprehensions go away silently along with their function
+        if !name.starts_with('-') {
+            report!(&func.name, "WARNING", W0001, "Function {name} is never used");
+        }
+    }
+}
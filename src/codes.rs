@@ -0,0 +1,615 @@
+// The stable codes of the diagnostics. The code stays the same when the
+// wording of the message changes, so it can be searched for and linked to.
+// The codes are never reused for something else. `bada --explain E0003`
+// prints the explanation.
+pub struct Code {
+    pub id: &'static str,
+    pub explanation: &'static str,
+}
+
+pub const E0001: Code = Code {id: "E0001", explanation: "\
+The parser found a token that can't be there.
+
+Erroneous code example:
+
+    inc(x int) = x + ;
+
+The message lists the tokens that could be there instead. Each definition of
+the module ends with `;`:
+
+    inc(x int) = x + 1;
+"};
+
+pub const E0002: Code = Code {id: "E0002", explanation: "\
+The number literal could not be parsed.
+
+Erroneous code example:
+
+    huge() = 999999999999999999999999999999999999999999;
+
+The integer literals must fit into 128 bits. The bigger numbers can still be
+computed at run time:
+
+    huge() = 999999999999999999999 * 999999999999999999999;
+"};
+
+pub const E0003: Code = Code {id: "E0003", explanation: "\
+The variable is not defined.
+
+Erroneous code example:
+
+    inc(x int) = y + 1;
+
+The variables are the parameters of the function and the names bound by the
+patterns of `case`, `receive`, `try` and the comprehensions. Check the
+spelling, or bind the variable first:
+
+    inc(x int) = x + 1;
+"};
+
+pub const E0004: Code = Code {id: "E0004", explanation: "\
+The called function is not defined.
+
+Erroneous code example:
+
+    hello() = wrold();
+    world() = 'world';
+
+The calls go to the functions of the module, the built-in functions and the
+funs held by the variables. Check the spelling of the function:
+
+    hello() = world();
+    world() = 'world';
+"};
+
+pub const E0005: Code = Code {id: "E0005", explanation: "\
+The type is not defined.
+
+Erroneous code example:
+
+    area(s shpe) = 0;
+
+The types are the built-in ones, like `int`, `float` or `list`, the records
+and the union types defined with `type`:
+
+    type shape = circle(float) | dot
+    area(s shape) = 0;
+"};
+
+pub const E0006: Code = Code {id: "E0006", explanation: "\
+The record is not defined.
+
+Erroneous code example:
+
+    record point { x int, y int }
+    origin() = pont{x: 0, y: 0};
+
+Check the spelling, or define the record with `record`:
+
+    record point { x int, y int }
+    origin() = point{x: 0, y: 0};
+"};
+
+pub const E0007: Code = Code {id: "E0007", explanation: "\
+The constructor in the pattern is not defined.
+
+Erroneous code example:
+
+    type result = ok(float) | error(atom)
+    unwrap(r result) = case r of
+        ok(value) -> value;
+        err(_) -> 0.0
+    end;
+
+The constructors are defined by the union types:
+
+    type result = ok(float) | error(atom)
+    unwrap(r result) = case r of
+        ok(value) -> value;
+        error(_) -> 0.0
+    end;
+"};
+
+pub const E0008: Code = Code {id: "E0008", explanation: "\
+The record has no field of that name.
+
+Erroneous code example:
+
+    record point { x int, y int }
+    depth(p point) = p.z;
+
+Only the fields listed in the definition of the record can be read and set:
+
+    record point { x int, y int }
+    depth(p point) = p.y;
+"};
+
+pub const E0009: Code = Code {id: "E0009", explanation: "\
+The function is defined again with a different number of parameters.
+
+Erroneous code example:
+
+    area(r float) = 3.14 * r * r;
+    area(w float h float) = w * h;
+
+Defining a function with the same name again adds one more clause to it, so
+all the clauses must have the same number of parameters. Give the other
+function its own name:
+
+    area(r float) = 3.14 * r * r;
+    rect_area(w float h float) = w * h;
+"};
+
+pub const E0010: Code = Code {id: "E0010", explanation: "\
+Two parameters of the function have the same name.
+
+Erroneous code example:
+
+    sum(x int x int) = x + x;
+
+Each parameter needs its own name:
+
+    sum(x int y int) = x + y;
+"};
+
+pub const E0011: Code = Code {id: "E0011", explanation: "\
+The record is defined twice.
+
+Erroneous code example:
+
+    record point { x int, y int }
+    record point { x float, y float }
+
+Give the other record its own name:
+
+    record point { x int, y int }
+    record fpoint { x float, y float }
+"};
+
+pub const E0012: Code = Code {id: "E0012", explanation: "\
+The union type is defined twice.
+
+Erroneous code example:
+
+    type shape = circle(float) | dot
+    type shape = square(float)
+
+All the constructors of the type go into one definition:
+
+    type shape = circle(float) | dot | square(float)
+"};
+
+pub const E0013: Code = Code {id: "E0013", explanation: "\
+The constructor is defined twice.
+
+Erroneous code example:
+
+    type result = ok(float) | error(atom)
+    type lookup = ok(int) | missing
+
+The constructors of all the types of the module share the names, because
+the pattern `ok(x)` has to know which type it matches. Give the other
+constructor its own name:
+
+    type result = ok(float) | error(atom)
+    type lookup = found(int) | missing
+"};
+
+pub const E0014: Code = Code {id: "E0014", explanation: "\
+The constant is defined twice.
+
+Erroneous code example:
+
+    const TIMEOUT = 5000;
+    const TIMEOUT = 1000;
+
+Give the other constant its own name:
+
+    const TIMEOUT = 5000;
+    const SHORT_TIMEOUT = 1000;
+"};
+
+pub const E0015: Code = Code {id: "E0015", explanation: "\
+The record has two fields with the same name.
+
+Erroneous code example:
+
+    record point { x int, x int }
+
+Each field needs its own name:
+
+    record point { x int, y int }
+"};
+
+pub const E0016: Code = Code {id: "E0016", explanation: "\
+The function has the same name as the constant.
+
+Erroneous code example:
+
+    const LIMIT = 10;
+    LIMIT() = 20;
+
+The use of `LIMIT` could mean either of them. Rename one of the definitions:
+
+    const LIMIT = 10;
+    default_limit() = 20;
+"};
+
+pub const E0017: Code = Code {id: "E0017", explanation: "\
+The function is called with the wrong number of arguments.
+
+Erroneous code example:
+
+    inc(x int) = x + 1;
+    two() = inc(1, 2);
+
+Pass as many arguments as there are parameters:
+
+    inc(x int) = x + 1;
+    two() = inc(1);
+"};
+
+pub const E0018: Code = Code {id: "E0018", explanation: "\
+The expression has a different type from what its place needs.
+
+Erroneous code example:
+
+    inc(x int) = x + 1;
+    one() = inc('zero');
+
+The labels of the diagnostic point at the places the conflicting types are
+inferred from. Pass the value of the right type:
+
+    inc(x int) = x + 1;
+    one() = inc(0);
+"};
+
+pub const E0019: Code = Code {id: "E0019", explanation: "\
+The branches of the function or the expression return different types.
+
+Erroneous code example:
+
+    sign(x int) when x < 0 = 0 - 1;
+    sign(x int) = 'positive';
+
+The clauses of a function, of `case` and `receive` and the catch clauses of
+`try` must all return the same type, because the caller can't tell which
+of them produced the value:
+
+    sign(x int) when x < 0 = 0 - 1;
+    sign(x int) = 1;
+"};
+
+pub const E0020: Code = Code {id: "E0020", explanation: "\
+The pattern can never match the value, because their types are different.
+
+Erroneous code example:
+
+    is_zero(x int) = case x of
+        'zero' -> true;
+        _ -> false
+    end;
+
+Match the values of the type of the subject:
+
+    is_zero(x int) = case x of
+        0 -> true;
+        _ -> false
+    end;
+"};
+
+pub const E0021: Code = Code {id: "E0021", explanation: "\
+The guard contains an expression that guards don't allow.
+
+Erroneous code example:
+
+    inc(x int) = x + 1;
+    small(x int) when inc(x) < 10 = true;
+    small(x int) = false;
+
+The guards are run by the BEAM while it picks the clause, so they can't
+call the functions of the module or use them as funs, send messages or use
+`case`, `receive`, `try` and the comprehensions. Of the built-in functions they may only call
+the type tests, like `is_integer`, and the sizes, like `length`. Compute the
+value in the body instead:
+
+    small(x int) when x + 1 < 10 = true;
+    small(x int) = false;
+"};
+
+pub const E0022: Code = Code {id: "E0022", explanation: "\
+The variable is called, but its value is not a fun.
+
+Erroneous code example:
+
+    apply(f int x int) = f(x);
+
+Only the variables holding funs can be called:
+
+    apply(f fn(int) -> int x int) = f(x);
+"};
+
+pub const E0023: Code = Code {id: "E0023", explanation: "\
+The field of the record is set more than once.
+
+Erroneous code example:
+
+    record point { x int, y int }
+    origin() = point{x: 0, x: 0, y: 0};
+
+Set each field once:
+
+    record point { x int, y int }
+    origin() = point{x: 0, y: 0};
+"};
+
+pub const E0024: Code = Code {id: "E0024", explanation: "\
+The new record does not set all of its fields.
+
+Erroneous code example:
+
+    record point { x int, y int }
+    origin() = point{x: 0};
+
+Set all the fields, or take the rest of them from another record with
+`point{p | x: 0}`:
+
+    record point { x int, y int }
+    origin() = point{x: 0, y: 0};
+"};
+
+pub const E0025: Code = Code {id: "E0025", explanation: "\
+The field of the record or the constructor has a type variable.
+
+Erroneous code example:
+
+    record box { value 'a }
+
+The records and the union types can't be generic yet. Use a concrete type:
+
+    record box { value int }
+"};
+
+pub const E0026: Code = Code {id: "E0026", explanation: "\
+The value of the constant depends on the constant itself.
+
+Erroneous code example:
+
+    const A = B + 1;
+    const B = A * 2;
+
+The constants are computed at compile time, so they can only refer to the
+constants that don't refer back to them:
+
+    const A = 2;
+    const B = A * 2;
+"};
+
+pub const E0027: Code = Code {id: "E0027", explanation: "\
+The value of the constant can't be computed at compile time.
+
+Erroneous code example:
+
+    inc(x int) = x + 1;
+    const ONE = inc(0);
+
+The values of the constants are made of literals, other constants and the
+arithmetic on them:
+
+    const ONE = 0 + 1;
+"};
+
+pub const E0028: Code = Code {id: "E0028", explanation: "\
+The binary pattern can't be matched.
+
+Erroneous code example:
+
+    split(<<head/binary, last:8>>) = {head, last};
+
+The segments of a binary pattern are matched from left to right, so the
+size of each segment but the last must be known. The segments may only
+match variables and numbers, their sizes must be numbers, the integer
+segments may have at most 64 bits, the whole pattern must have fewer than 2^59
+bits and the float segments are not supported:
+
+    split(<<first:8, rest/binary>>) = {first, rest};
+"};
+
+pub const E0029: Code = Code {id: "E0029", explanation: "\
+The type specifier of the binary segment is not valid.
+
+Erroneous code example:
+
+    header(version int) = <<version:8/bigg>>;
+
+The specifiers are separated by `-` after `/`. They are the types `integer`,
+`float`, `binary`, `bytes`, `bits` and `bitstring`, the signedness `signed`
+and `unsigned`, the endianness `big`, `little` and `native`, and the unit
+`unit:N`, where N is a number from 1 to 256:
+
+    header(version int) = <<version:8/big>>;
+"};
+
+pub const E0030: Code = Code {id: "E0030", explanation: "\
+The key of the map pattern is not a literal.
+
+Erroneous code example:
+
+    lookup(key atom #{key := value}) = value;
+
+The keys of map patterns must be numbers, booleans or atoms:
+
+    name(#{'name' := value}) = value;
+"};
+
+pub const E0031: Code = Code {id: "E0031", explanation: "\
+The new map uses `:=`.
+
+Erroneous code example:
+
+    user(name atom) = #{'name' := name};
+
+`:=` updates the keys that are already in the map, so the new map has none
+to update. Add the keys with `=>`:
+
+    user(name atom) = #{'name' => name};
+"};
+
+pub const E0032: Code = Code {id: "E0032", explanation: "\
+The class of the exception in the catch clause is not known.
+
+Erroneous code example:
+
+    safe_div(a int b int) = try a / b catch
+        panic:reason -> 0.0
+    end;
+
+The classes of the exceptions are `throw`, `error` and `exit`. `_` matches
+any of them, and the clause with no class catches `throw`:
+
+    safe_div(a int b int) = try a / b catch
+        error:reason -> 0.0
+    end;
+"};
+
+pub const E0033: Code = Code {id: "E0033", explanation: "\
+Some clauses of the function are private and the others are not.
+
+Erroneous code example:
+
+    private sign(x int) when x < 0 = 0 - 1;
+    sign(x int) = 1;
+
+The function is either exported from the module or not, so mark all of its
+clauses the same way:
+
+    private sign(x int) when x < 0 = 0 - 1;
+    private sign(x int) = 1;
+"};
+
+pub const E0034: Code = Code {id: "E0034", explanation: "\
+The function has the same name as the constructor of a union type.
+
+Erroneous code example:
+
+    type result = ok(float) | error(atom)
+    ok(x float) = {'ok', x};
+
+The call `ok(x)` could mean either of them. Rename one of the definitions:
+
+    type result = ok(float) | error(atom)
+    make_ok(x float) = {'ok', x};
+"};
+
+pub const E0035: Code = Code {id: "E0035", explanation: "\
+The function has the same name and arity as the built-in function.
+
+Erroneous code example:
+
+    length(l list) = 0;
+
+The call `length(l)` could mean either of them. Rename the function:
+
+    empty_length(l list) = 0;
+"};
+
+pub const E0036: Code = Code {id: "E0036", explanation: "\
+The union type has the same name as the record.
+
+Erroneous code example:
+
+    record shape { width int, height int }
+    type shape = circle(float) | square(float)
+
+The type `shape` could mean either of them. Rename one of the definitions:
+
+    record rect { width int, height int }
+    type shape = circle(float) | square(float)
+"};
+
+pub const E0037: Code = Code {id: "E0037", explanation: "\
+The constructor is called or matched with the wrong number of arguments.
+
+Erroneous code example:
+
+    type result = ok(float) | error(atom)
+    success() = ok(1.0, 2.0);
+
+Pass as many arguments as the constructor has fields:
+
+    type result = ok(float) | error(atom)
+    success() = ok(1.0);
+"};
+
+pub const E0038: Code = Code {id: "E0038", explanation: "\
+The fun is called with the wrong number of arguments.
+
+Erroneous code example:
+
+    apply(f fn(int) -> int x int) = f(x, x);
+
+Pass as many arguments as there are parameters in the type of the fun:
+
+    apply(f fn(int) -> int x int) = f(x);
+"};
+
+pub const E0039: Code = Code {id: "E0039", explanation: "\
+The field of the record is set by something other than its name.
+
+Erroneous code example:
+
+    record point { x int, y int }
+    origin() = point{'x': 0, y: 0};
+
+The fields are set by their names, without the quotes:
+
+    record point { x int, y int }
+    origin() = point{x: 0, y: 0};
+"};
+
+pub const W0001: Code = Code {id: "W0001", explanation: "\
+The private function is never called by the exported functions, so it is
+left out of the compiled module.
+
+Erroneous code example:
+
+    private double(x int) = x * 2;
+    inc(x int) = x + 1;
+
+Remove the function, or call it from the functions that are used:
+
+    private double(x int) = x * 2;
+    inc(x int) = double(x) + 1;
+"};
+
+pub const W0002: Code = Code {id: "W0002", explanation: "\
+The `case` does not handle some of the constructors of the union type. The
+value made by them raises `case_clause` at run time.
+
+Erroneous code example:
+
+    type result = ok(float) | error(atom)
+    unwrap(r result) = case r of
+        ok(value) -> value
+    end;
+
+Handle the rest of the constructors, or add the catch-all clause `_`:
+
+    type result = ok(float) | error(atom)
+    unwrap(r result) = case r of
+        ok(value) -> value;
+        error(_) -> 0.0
+    end;
+"};
+
+pub const CODES: &[Code] = &[
+    E0001, E0002, E0003, E0004, E0005, E0006, E0007, E0008, E0009, E0010,
+    E0011, E0012, E0013, E0014, E0015, E0016, E0017, E0018, E0019, E0020,
+    E0021, E0022, E0023, E0024, E0025, E0026, E0027, E0028, E0029, E0030,
+    E0031, E0032, E0033, E0034, E0035, E0036, E0037, E0038, E0039,
+    W0001, W0002,
+];
+
+pub fn find(id: &str) -> Option<&'static Code> {
+    CODES.iter().find(|code| code.id == id)
+}
@@ -1,47 +1,24 @@
-use diag::*;
-use std::collections::HashMap;
-use parser::{Expr, Module, Func, BinopKind, Binop, Param};
-
-#[repr(u8)]
-enum Tag {
-    U = 0,                      // unsigned?
-    I = 1,                      // integer
-    A = 2,                      // atom
-    X = 3,                      // x register
-    // Y = 4,                   // y register
-    F = 5,                      // label
-    // H = 6,                   // character?
-    // Z = 7,                   // ?
-}
-
-fn encode_arg(tag: Tag, n: i32) -> Vec<u8> {
-    if n < 0 {
-        todo!("negative");
-    } else if n < 16 {
-        // (N bsl 4) bor Tag;
-        let tag = tag as u8;
-        let n = n as u8;
-        vec![(n<<4)|tag]
-    } else if n < 0x800 {
-        // [((N bsr 3) band 2#11100000) bor Tag bor 2#00001000, N band 16#ff];
-        let tag = tag as u32;
-        let n = n as u32;
-        let a = (((n>>3)&0b11100000u32)|tag|0b00001000u32) as u8;
-        let b = (n&0xFF) as u8;
-        vec![a, b]
-    } else {
-        todo!("large numbers");
-    }
+use std::collections::{HashMap, BTreeMap};
+use builtins::{self, Lowering};
+use lex::Token;
+use ir::{Instr, Operand, OpCode, encode_code};
+use peephole;
+use parser::{Expr, ExprKind, Module, FuncClause, BinopKind, Binop, Params, Type, Pattern, PatternKind, MatchClause, CatchClause, MapPair, Segment, SegmentSpec, SegmentKind, Endianness, After, MAX_SEGMENT_BITS};
+
+pub struct Options {
+    /// Check the types of the annotated parameters on the entry to the function
+    pub type_guards: bool,
+    /// Inline the small functions and clean up the generated instructions with the peephole optimiser
+    pub optimize: bool,
 }
 
-#[repr(u8)]
-enum OpCode {
-    Label = 1,
-    FuncInfo = 2,
-    IntCodeEnd = 3,
-    Return = 19,
-    Move = 64,
-    GcBif2 = 125,
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            type_guards: true,
+            optimize: true,
+        }
+    }
 }
 
 // aaaa|aaaa|a000|
@@ -61,63 +38,936 @@ fn encode_chunk(tag: [u8; 4], chunk: Vec<u8>) -> Vec<u8> {
 }
 
 
-fn compile_expr(expr: &Expr, atoms: &mut Atoms, imports: &HashMap<(u32, u32, u32), u32>, code: &mut Vec<u8>, params: &HashMap<String, Param>, stack_size: &mut usize) -> Option<()> {
-    let stack_start = params.len();
-    match expr {
-        Expr::Var(name) => {
-            match params.get(&name.text) {
-                Some(param) => {
-                    code.push(OpCode::Move as u8);
-                    code.extend(encode_arg(Tag::X, param.index as i32));
-                    code.extend(encode_arg(Tag::X, (stack_start + *stack_size) as i32));
-                    *stack_size += 1;
+// Tables shared by all the functions of the module
+#[derive(Default)]
+struct Context {
+    atoms: Atoms,
+    imports: Imports,
+    literals: Literals,
+    lambdas: Lambdas,
+    funcs: BTreeMap<String, CompiledFunc>,
+    // The names of the fields of the records in the order of the tuple elements
+    records: HashMap<String, Vec<String>>,
+    // The arities of the constructors of the union types
+    constructors: HashMap<String, usize>,
+    label_count: u32,
+}
+
+impl Context {
+    fn new_label(&mut self) -> u32 {
+        self.label_count += 1;
+        self.label_count
+    }
+}
+
+// The values of expressions are computed on a stack of slots. The bottom
+// of the stack are the parameters of the function. If the function never
+// clobbers the x registers the slots are just x registers. Otherwise
+// they are y registers of the stack frame of the function.
+struct Scope {
+    vars: Vec<(String, usize)>,
+    stack_size: usize,
+    max_stack_size: usize,
+    frame: bool,
+    // Where the failing BIFs and record checks jump. 0 raises the exception,
+    // the guards set it to the label of the next clause.
+    fail: u32,
+}
+
+impl Scope {
+    fn new(params: &Params) -> Self {
+        let vars = params.names().cloned().zip(0..).collect();
+        Self {
+            vars,
+            stack_size: params.len(),
+            max_stack_size: params.len(),
+            frame: false,
+            fail: 0,
+        }
+    }
+
+    fn arg(&self, slot: usize) -> Operand {
+        if self.frame { Operand::Y(slot) } else { Operand::X(slot) }
+    }
+
+    // The slot `depth` values below the top of the stack. top(1) is the last pushed one.
+    fn top(&self, depth: usize) -> usize {
+        assert!(self.stack_size >= depth);
+        self.stack_size - depth
+    }
+
+    fn push(&mut self) -> usize {
+        self.stack_size += 1;
+        self.max_stack_size = self.max_stack_size.max(self.stack_size);
+        self.stack_size - 1
+    }
+
+    fn pop(&mut self, n: usize) {
+        assert!(self.stack_size >= n);
+        self.stack_size -= n;
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.vars
+            .iter()
+            .rev()
+            .find(|(var, _)| var == name)
+            .map(|(_, slot)| *slot)
+    }
+
+    // How many x registers must survive the GC if everything below the `slot` is alive
+    fn live(&self, slot: usize) -> usize {
+        if self.frame { 0 } else { slot }
+    }
+}
+
+// Whether the expression needs a stack frame, because it clobbers the x registers
+fn needs_frame(expr: &Expr, ctx: &Context) -> bool {
+    match &expr.kind {
+        ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Atom(_) => false,
+        // Making a fun may trigger the GC that does not know which x registers are alive.
+        // A variable that shadows a function is treated the same way, which is just a bit wasteful.
+        ExprKind::Var(name) => ctx.funcs.contains_key(&name.text),
+        ExprKind::Tuple(elements) => elements.iter().any(|element| needs_frame(element, ctx)),
+        ExprKind::Record{base, fields, ..} => {
+            base.as_ref().map(|base| needs_frame(base, ctx)).unwrap_or(false) || fields.iter().any(|(_, value)| needs_frame(value, ctx))
+        }
+        ExprKind::Field{record, ..} => needs_frame(record, ctx),
+        ExprKind::List{elements, tail} => {
+            elements.iter().any(|element| needs_frame(element, ctx)) || tail.as_ref().map(|tail| needs_frame(tail, ctx)).unwrap_or(false)
+        }
+        ExprKind::Binary(segments) => {
+            segments.iter().any(|segment| {
+                needs_frame(&segment.value, ctx) || segment.size.as_ref().map(|size| needs_frame(size, ctx)).unwrap_or(false)
+            })
+        }
+        ExprKind::Map{base, pairs} => {
+            base.as_ref().map(|base| needs_frame(base, ctx)).unwrap_or(false) || pairs.iter().any(|pair| {
+                needs_frame(&pair.key, ctx) || needs_frame(&pair.value, ctx)
+            })
+        }
+        ExprKind::Binop(Binop{lhs, rhs, ..}) => needs_frame(lhs, ctx) || needs_frame(rhs, ctx),
+        ExprKind::Not(operand) => needs_frame(operand, ctx),
+        ExprKind::Case{subject, clauses} => {
+            needs_frame(subject, ctx) || clauses.iter().any(|clause| {
+                clause.guard.as_ref().map(|guard| needs_frame(guard, ctx)).unwrap_or(false) || needs_frame(&clause.body, ctx)
+            })
+        }
+        ExprKind::Call{name, args} if ctx.constructors.contains_key(&name.text) => args.iter().any(|arg| needs_frame(arg, ctx)),
+        // The guard BIFs are computed in place, just like the operators
+        ExprKind::Call{name, args} => match builtins::find(&name.text, args.len()) {
+            Some(builtin) if builtin.is_guard_safe() => args.iter().any(|arg| needs_frame(arg, ctx)),
+            _ => true,
+        },
+        ExprKind::Send{..} | ExprKind::Receive{..} | ExprKind::Try{..} | ExprKind::Comprehension{..} => true,
+    }
+}
+
+fn bool_arg(ctx: &mut Context, x: bool) -> Operand {
+    Operand::Atom(ctx.atoms.get_id(if x { "true" } else { "false" }))
+}
+
+fn comparison_bif(kind: &BinopKind) -> &'static str {
+    match kind {
+        BinopKind::Eq => "==",
+        BinopKind::NotEq => "/=",
+        BinopKind::Less => "<",
+        BinopKind::LessEq => "=<",
+        BinopKind::Greater => ">",
+        BinopKind::GreaterEq => ">=",
+        _ => unreachable!("not a comparison"),
+    }
+}
+
+// Builds a tuple out of the values from the `first` slot up to the top of the
+// stack. The tuple replaces them in the `first` slot.
+fn emit_put_tuple(first: usize, code: &mut Vec<Instr>, scope: &mut Scope) {
+    let size = scope.stack_size - first;
+    // The tuple takes its header word plus a word per element on the heap
+    code.push(Instr::TestHeap{alloc: size + 1, live: scope.live(scope.stack_size)});
+    code.push(Instr::PutTuple2 {
+        dst: scope.arg(first),
+        elements: (first..scope.stack_size).map(|slot| scope.arg(slot)).collect(),
+    });
+    scope.pop(size - 1);
+}
+
+// Raises {badrecord, Value} unless the value in the slot is the record
+fn emit_record_check(name: &str, slot: usize, ctx: &mut Context, code: &mut Vec<Instr>, scope: &mut Scope) {
+    let args = vec![scope.arg(slot), Operand::Unsigned(ctx.records[name].len() + 1), Operand::Atom(ctx.atoms.get_id(name))];
+    // In the guard another value just does not match the clause
+    if scope.fail != 0 {
+        code.push(Instr::Test{op: OpCode::IsTaggedTuple, fail: scope.fail, args});
+        return
+    }
+    let bad_label = ctx.new_label();
+    let ok_label = ctx.new_label();
+    code.push(Instr::Test{op: OpCode::IsTaggedTuple, fail: bad_label, args});
+    code.push(Instr::Jump(ok_label));
+    code.push(Instr::Label(bad_label));
+    code.push(Instr::BadRecord(scope.arg(slot)));
+    code.push(Instr::Label(ok_label));
+}
+
+// `send` takes the receiver from x0 and the message from x1, and leaves the message in x0
+fn compile_send(pid: &Expr, msg: &Expr, ctx: &mut Context, code: &mut Vec<Instr>, scope: &mut Scope) -> Option<()> {
+    compile_expr(pid, ctx, code, scope)?;
+    compile_expr(msg, ctx, code, scope)?;
+    code.push(Instr::Move{src: scope.arg(scope.top(2)), dst: Operand::X(0)});
+    code.push(Instr::Move{src: scope.arg(scope.top(1)), dst: Operand::X(1)});
+    code.push(Instr::Send);
+    code.push(Instr::Move{src: Operand::X(0), dst: scope.arg(scope.top(2))});
+    scope.pop(1);
+    Some(())
+}
+
+fn segment_type_arg(spec: &SegmentSpec, ctx: &mut Context) -> Operand {
+    let name = match spec.kind {
+        SegmentKind::Integer => "integer",
+        SegmentKind::Float => "float",
+        SegmentKind::Binary | SegmentKind::Bits => "binary",
+    };
+    Operand::Atom(ctx.atoms.get_id(name))
+}
+
+// The flags are the list of atoms, where the default ones (big and unsigned) are omitted
+fn segment_flags_arg(spec: &SegmentSpec, ctx: &mut Context) -> Operand {
+    let mut flags = Vec::new();
+    match spec.endianness {
+        Endianness::Big => {}
+        Endianness::Little => flags.push("little"),
+        Endianness::Native => flags.push("native"),
+    }
+    if spec.signed {
+        flags.push("signed");
+    }
+    if flags.is_empty() {
+        Operand::Nil
+    } else {
+        Operand::Literal(ctx.literals.get_id(encode_atom_list_term(&flags)))
+    }
+}
+
+fn pattern_literal_arg(pattern: &Pattern, ctx: &mut Context) -> Operand {
+    match &pattern.kind {
+        PatternKind::Number(x) => Operand::Int(*x as i128),
+        PatternKind::Bool(x) => bool_arg(ctx, *x),
+        PatternKind::Atom(name) => Operand::Atom(ctx.atoms.get_id(name)),
+        _ => unreachable!("not a literal pattern"),
+    }
+}
+
+// Matches the value in the `src` slot against the pattern. Jumps to
+// fail_label on mismatch. The variables of the pattern are bound in the scope.
+fn compile_pattern(pattern: &Pattern, src: usize, fail_label: u32, ctx: &mut Context, code: &mut Vec<Instr>, scope: &mut Scope) -> Option<()> {
+    let literal = match &pattern.kind {
+        PatternKind::Wildcard => return Some(()),
+        PatternKind::Var(name) => {
+            match scope.lookup(&name.text) {
+                Some(slot) => scope.arg(slot),
+                None => {
+                    scope.vars.push((name.text.clone(), src));
+                    return Some(())
+                }
+            }
+        }
+        PatternKind::Number(_) | PatternKind::Bool(_) | PatternKind::Atom(_) => pattern_literal_arg(pattern, ctx),
+        PatternKind::Constructor{name, args} if args.is_empty() => Operand::Atom(ctx.atoms.get_id(&name.text)),
+        PatternKind::Constructor{name, args} => {
+            code.push(Instr::Test {
+                op: OpCode::IsTaggedTuple,
+                fail: fail_label,
+                args: vec![scope.arg(src), Operand::Unsigned(args.len() + 1), Operand::Atom(ctx.atoms.get_id(&name.text))],
+            });
+            for (index, arg) in args.iter().enumerate() {
+                if let PatternKind::Wildcard = arg.kind {
+                    continue
+                }
+                // The first element is the tag
+                let slot = scope.push();
+                code.push(Instr::GetTupleElement{src: scope.arg(src), index: index + 1, dst: scope.arg(slot)});
+                compile_pattern(arg, slot, fail_label, ctx, code, scope)?;
+            }
+            return Some(())
+        }
+        PatternKind::Map(pairs) => {
+            code.push(Instr::Test{op: OpCode::IsMap, fail: fail_label, args: vec![scope.arg(src)]});
+            if pairs.is_empty() {
+                return Some(())
+            }
+            // All the values are fetched at once into the consecutive slots,
+            // which stay occupied until the end of the clause
+            let first = scope.stack_size;
+            let mut list = Vec::new();
+            for (key, _) in pairs.iter() {
+                let slot = scope.push();
+                list.push(pattern_literal_arg(key, ctx));
+                list.push(scope.arg(slot));
+            }
+            code.push(Instr::GetMapElements{fail: fail_label, src: scope.arg(src), pairs: list});
+            for (index, (_, value)) in pairs.iter().enumerate() {
+                compile_pattern(value, first + index, fail_label, ctx, code, scope)?;
+            }
+            return Some(())
+        }
+        PatternKind::Binary(segments) => {
+            let match_context = scope.push();
+            code.push(Instr::BsStartMatch4 {
+                fail: fail_label,
+                live: scope.live(match_context),
+                src: scope.arg(src),
+                dst: scope.arg(match_context),
+            });
+
+            // The sizes of all the segments except the tail are known at compile time,
+            // so a single check upfront makes sure there are enough bits for all of them
+            let mut fixed_bits: usize = 0;
+            for segment in segments.iter() {
+                let size = match &segment.size {
+                    Some(Pattern{kind: PatternKind::Number(size), ..}) => Some(*size),
+                    Some(_) => unreachable!("the parser only allows numbers"),
+                    None => segment.spec.default_size(),
+                };
+                if let Some(size) = size {
+                    fixed_bits = match size.checked_mul(segment.spec.unit()).and_then(|bits| bits.checked_add(fixed_bits)) {
+                        Some(bits) if bits <= MAX_SEGMENT_BITS => bits,
+                        _ => {
+                            report!(pattern, "ERROR", E0028, "Binary patterns may have at most {MAX_SEGMENT_BITS} bits in the segments of fixed size");
+                            return None
+                        }
+                    };
+                }
+            }
+            let mut commands = Vec::new();
+            match segments.last() {
+                Some(tail) if tail.size.is_none() && tail.spec.default_size().is_none() => {
+                    commands.push(Operand::Atom(ctx.atoms.get_id("ensure_at_least")));
+                    commands.push(Operand::Unsigned(fixed_bits));
+                    commands.push(Operand::Unsigned(tail.spec.unit()));
+                }
+                _ => {
+                    commands.push(Operand::Atom(ctx.atoms.get_id("ensure_exactly")));
+                    commands.push(Operand::Unsigned(fixed_bits));
+                }
+            }
+
+            let mut values = Vec::new();
+            for segment in segments.iter() {
+                let size = match &segment.size {
+                    Some(Pattern{kind: PatternKind::Number(size), ..}) => Some(*size),
+                    _ => segment.spec.default_size(),
+                };
+                if let PatternKind::Wildcard = segment.value.kind {
+                    if let Some(size) = size {
+                        commands.push(Operand::Atom(ctx.atoms.get_id("skip")));
+                        commands.push(Operand::Unsigned(size*segment.spec.unit()));
+                    }
+                    continue
+                }
+                // The slots stay occupied until the end of the clause, because
+                // the segments may be bound to variables
+                let slot = scope.push();
+                match size {
+                    Some(size) => {
+                        commands.push(segment_type_arg(&segment.spec, ctx));
+                        commands.push(Operand::Unsigned(scope.live(slot))); // Live
+                        commands.push(segment_flags_arg(&segment.spec, ctx));
+                        commands.push(Operand::Unsigned(size)); // Size
+                        commands.push(Operand::Unsigned(segment.spec.unit())); // Unit
+                        commands.push(scope.arg(slot)); // Dst
+                    }
+                    None => {
+                        commands.push(Operand::Atom(ctx.atoms.get_id("get_tail")));
+                        commands.push(Operand::Unsigned(scope.live(slot))); // Live
+                        commands.push(Operand::Unsigned(segment.spec.unit())); // Unit
+                        commands.push(scope.arg(slot)); // Dst
+                    }
+                }
+                values.push((&segment.value, slot));
+            }
+            code.push(Instr::BsMatch{fail: fail_label, context: scope.arg(match_context), commands});
+            for (value, slot) in values {
+                compile_pattern(value, slot, fail_label, ctx, code, scope)?;
+            }
+            return Some(())
+        }
+        PatternKind::List{elements, tail} => {
+            // Each element takes the head off the rest of the list
+            let mut rest = src;
+            for element in elements.iter() {
+                code.push(Instr::Test{op: OpCode::IsNonemptyList, fail: fail_label, args: vec![scope.arg(rest)]});
+                // The slots stay occupied until the end of the clause, because
+                // the head and the tail may be bound to variables
+                let head = scope.push();
+                let tail = scope.push();
+                code.push(Instr::GetList{src: scope.arg(rest), head: scope.arg(head), tail: scope.arg(tail)});
+                compile_pattern(element, head, fail_label, ctx, code, scope)?;
+                rest = tail;
+            }
+            match tail {
+                Some(tail) => compile_pattern(tail, rest, fail_label, ctx, code, scope)?,
+                None => code.push(Instr::Test{op: OpCode::IsNil, fail: fail_label, args: vec![scope.arg(rest)]}),
+            }
+            return Some(())
+        }
+        PatternKind::Tuple(elements) => {
+            code.push(Instr::Test{op: OpCode::IsTuple, fail: fail_label, args: vec![scope.arg(src)]});
+            code.push(Instr::Test{op: OpCode::TestArity, fail: fail_label, args: vec![scope.arg(src), Operand::Unsigned(elements.len())]});
+            for (index, element) in elements.iter().enumerate() {
+                if let PatternKind::Wildcard = element.kind {
+                    continue
+                }
+                // The slot stays occupied until the end of the clause, because
+                // the element may be bound to a variable
+                let slot = scope.push();
+                code.push(Instr::GetTupleElement{src: scope.arg(src), index, dst: scope.arg(slot)});
+                compile_pattern(element, slot, fail_label, ctx, code, scope)?;
+            }
+            return Some(())
+        }
+    };
+    code.push(Instr::Test{op: OpCode::IsEqExact, fail: fail_label, args: vec![scope.arg(src), literal]});
+    Some(())
+}
+
+// Compiles the clauses matching the value in the `src` slot. The result of
+// the matched clause is put into the `dst` slot and the control jumps to
+// end_label. Falls through if none of the clauses match. `on_match` is emitted
+// after the pattern and the guard matched, but before the body.
+#[allow(clippy::too_many_arguments)]
+fn compile_clauses(clauses: &[MatchClause], src: usize, dst: usize, end_label: u32, on_match: Option<Instr>, ctx: &mut Context, code: &mut Vec<Instr>, scope: &mut Scope) -> Option<()> {
+    for MatchClause{pattern, guard, body} in clauses.iter() {
+        let (vars_len, stack_size) = (scope.vars.len(), scope.stack_size);
+        let next_clause_label = ctx.new_label();
+        compile_pattern(pattern, src, next_clause_label, ctx, code, scope)?;
+        if let Some(guard) = guard {
+            compile_guard(guard, next_clause_label, ctx, code, scope)?;
+        }
+        if let Some(instr) = &on_match {
+            code.push(instr.clone());
+        }
+        compile_expr(body, ctx, code, scope)?;
+        code.push(Instr::Move{src: scope.arg(scope.top(1)), dst: scope.arg(dst)});
+        code.push(Instr::Jump(end_label));
+        code.push(Instr::Label(next_clause_label));
+        scope.vars.truncate(vars_len);
+        scope.stack_size = stack_size;
+    }
+    Some(())
+}
+
+fn compile_call(expr: &Expr, name: &Token, args: &[Expr], ctx: &mut Context, code: &mut Vec<Instr>, scope: &mut Scope) -> Option<()> {
+    // The constructors make the atoms and the tagged tuples just like the records.
+    // They shadow the built-in functions, like error/1.
+    if ctx.constructors.contains_key(&name.text) {
+        let first = scope.push();
+        code.push(Instr::Move{src: Operand::Atom(ctx.atoms.get_id(&name.text)), dst: scope.arg(first)});
+        if !args.is_empty() {
+            for arg in args.iter() {
+                compile_expr(arg, ctx, code, scope)?;
+            }
+            emit_put_tuple(first, code, scope);
+        }
+        return Some(())
+    }
+
+    let builtin = builtins::find(&name.text, args.len());
+    match builtin.map(|builtin| &builtin.lowering) {
+        Some(Lowering::Send) => return compile_send(&args[0], &args[1], ctx, code, scope),
+        Some(Lowering::Bif0) => {
+            let builtin = builtin.unwrap();
+            let bif = ctx.imports.get_id(&mut ctx.atoms, builtin.module, builtin.func, 0);
+            let dst = scope.push();
+            code.push(Instr::Bif0{bif, dst: scope.arg(dst)});
+            return Some(())
+        }
+        Some(Lowering::Bif1) | Some(Lowering::GcBif1) => {
+            let builtin = builtin.unwrap();
+            compile_expr(&args[0], ctx, code, scope)?;
+            let bif = ctx.imports.get_id(&mut ctx.atoms, builtin.module, builtin.func, 1);
+            let arg = scope.arg(scope.top(1));
+            code.push(if let Lowering::Bif1 = builtin.lowering {
+                Instr::Bif1{fail: scope.fail, bif, arg: arg.clone(), dst: arg}
+            } else {
+                Instr::GcBif1{fail: scope.fail, live: scope.live(scope.top(1)), bif, arg: arg.clone(), dst: arg}
+            });
+            return Some(())
+        }
+        Some(Lowering::CallExt) | None => {}
+    }
+
+    // The rest are the real calls that take the arguments from the x
+    // registers, return the result in x0 and clobber everything else
+    assert!(scope.frame, "call clobbers x registers");
+    for arg in args.iter() {
+        compile_expr(arg, ctx, code, scope)?;
+    }
+    let first = scope.top(args.len());
+    for (index, slot) in (first..scope.stack_size).enumerate() {
+        code.push(Instr::Move{src: scope.arg(slot), dst: Operand::X(index)});
+    }
+    // The variables holding funs shadow the functions of the module
+    match (builtin, scope.lookup(&name.text)) {
+        (None, Some(fun)) => {
+            // call_fun takes the fun from the x register right after the arguments
+            code.push(Instr::Move{src: scope.arg(fun), dst: Operand::X(args.len())});
+            code.push(Instr::CallFun{arity: args.len()});
+        }
+        (Some(builtin), _) => {
+            let import = ctx.imports.get_id(&mut ctx.atoms, builtin.module, builtin.func, args.len() as u32);
+            code.push(Instr::CallExt{arity: args.len(), import});
+        }
+        (None, None) => {
+            let label = match ctx.funcs.get(&name.text) {
+                Some(func) if func.arity as usize == args.len() => func.label,
+                _ => {
+                    report!(&expr, "ERROR", E0004, "Unknown function {name}/{arity}", name = name.text, arity = args.len());
+                    return None
+                }
+            };
+            code.push(Instr::Call{arity: args.len(), label});
+        }
+    }
+    scope.pop(args.len());
+    let dst = scope.push();
+    code.push(Instr::Move{src: Operand::X(0), dst: scope.arg(dst)});
+    Some(())
+}
+
+fn compile_expr(expr: &Expr, ctx: &mut Context, code: &mut Vec<Instr>, scope: &mut Scope) -> Option<()> {
+    match &expr.kind {
+        ExprKind::Var(name) => {
+            match scope.lookup(&name.text) {
+                Some(slot) => {
+                    let dst = scope.push();
+                    code.push(Instr::Move{src: scope.arg(slot), dst: scope.arg(dst)});
+                    Some(())
+                }
+                None if ctx.funcs.contains_key(&name.text) => {
+                    assert!(scope.frame, "make_fun3 clobbers x registers");
+                    let CompiledFunc{name_id, label, arity, ..} = ctx.funcs[&name.text];
+                    let dst = scope.push();
+                    code.push(Instr::MakeFun3{lambda: ctx.lambdas.get_id(name_id, arity, label), dst: scope.arg(dst), free: vec![]});
                     Some(())
                 }
                 None => {
-                    report!(&name.loc, "ERROR", "Unknown variable {name}", name = name.text);
+                    report!(&name, "ERROR", E0003, "Unknown variable {name}", name = name.text);
                     None
                 }
             }
         }
-        Expr::Number(x) => {
-            code.push(OpCode::Move as u8);
-            code.extend(encode_arg(Tag::I, (*x) as i32));
-            code.extend(encode_arg(Tag::X, (stack_start + *stack_size) as i32));
-            *stack_size += 1;
+        ExprKind::Number(x) => {
+            let dst = scope.push();
+            code.push(Instr::Move{src: Operand::Int(*x), dst: scope.arg(dst)});
+            Some(())
+        },
+        ExprKind::Float(x) => {
+            let dst = scope.push();
+            code.push(Instr::Move{src: Operand::Literal(ctx.literals.get_id(encode_float_term(*x))), dst: scope.arg(dst)});
             Some(())
         },
-        Expr::Binop(Binop{kind, lhs, rhs}) => {
-            compile_expr(lhs, atoms, imports, code, params, stack_size)?;
-            compile_expr(rhs, atoms, imports, code, params, stack_size)?;
-
-            assert!(*stack_size >= 2);
-
-            code.push(OpCode::GcBif2 as u8);
-            code.extend(encode_arg(Tag::F, 0)); // Lbl
-            code.extend(encode_arg(Tag::U, 2)); // Live
-            let bif2 = match kind {
-                BinopKind::Sum => imports
-                    .get(&resolve_function_signature(atoms, "erlang", "+", 2))
-                    .cloned()
-                    .expect("erlang:'+' should be always present"),
-                BinopKind::Sub => imports
-                    .get(&resolve_function_signature(atoms, "erlang", "-", 2))
-                    .cloned()
-                    .expect("erlang:'-' should be always present"),
+        ExprKind::Bool(x) => {
+            let dst = scope.push();
+            code.push(Instr::Move{src: bool_arg(ctx, *x), dst: scope.arg(dst)});
+            Some(())
+        },
+        ExprKind::Atom(name) => {
+            let dst = scope.push();
+            code.push(Instr::Move{src: Operand::Atom(ctx.atoms.get_id(name)), dst: scope.arg(dst)});
+            Some(())
+        },
+        ExprKind::Tuple(elements) => {
+            if elements.is_empty() {
+                let dst = scope.push();
+                code.push(Instr::Move{src: Operand::Literal(ctx.literals.get_id(encode_empty_tuple_term())), dst: scope.arg(dst)});
+                return Some(())
+            }
+
+            for element in elements.iter() {
+                compile_expr(element, ctx, code, scope)?;
+            }
+            emit_put_tuple(scope.top(elements.len()), code, scope);
+            Some(())
+        }
+        ExprKind::List{elements, tail} => {
+            if elements.is_empty() {
+                match tail {
+                    Some(tail) => return compile_expr(tail, ctx, code, scope),
+                    None => {
+                        let dst = scope.push();
+                        code.push(Instr::Move{src: Operand::Nil, dst: scope.arg(dst)});
+                        return Some(())
+                    }
+                }
+            }
+            // The list is built from the end, each cell replacing its head on
+            // the stack, so the whole list ends up in the slot of the first element
+            let first = scope.stack_size;
+            for element in elements.iter() {
+                compile_expr(element, ctx, code, scope)?;
+            }
+            let mut rest = match tail {
+                Some(tail) => {
+                    compile_expr(tail, ctx, code, scope)?;
+                    scope.arg(scope.top(1))
+                }
+                None => Operand::Nil,
+            };
+            // Each cell takes two words on the heap
+            code.push(Instr::TestHeap{alloc: 2*elements.len(), live: scope.live(scope.stack_size)});
+            for slot in (first..first + elements.len()).rev() {
+                code.push(Instr::PutList{head: scope.arg(slot), tail: rest, dst: scope.arg(slot)});
+                rest = scope.arg(slot);
+            }
+            scope.pop(scope.stack_size - first - 1);
+            Some(())
+        }
+        ExprKind::Comprehension{..} => unreachable!("comprehensions are desugared before compilation"),
+        ExprKind::Map{base, pairs} => {
+            let base_arg = match base {
+                Some(base) => {
+                    compile_expr(base, ctx, code, scope)?;
+                    scope.arg(scope.top(1))
+                }
+                None if pairs.is_empty() => {
+                    let dst = scope.push();
+                    code.push(Instr::Move{src: Operand::Literal(ctx.literals.get_id(encode_empty_map_term())), dst: scope.arg(dst)});
+                    return Some(())
+                }
+                None => Operand::Literal(ctx.literals.get_id(encode_empty_map_term())),
             };
-            code.extend(encode_arg(Tag::U, bif2 as i32)); // Bif
-            code.extend(encode_arg(Tag::X, (stack_start + *stack_size - 2) as i32)); // Arg1
-            code.extend(encode_arg(Tag::X, (stack_start + *stack_size - 1) as i32)); // Arg2
-            code.extend(encode_arg(Tag::X, (stack_start + *stack_size - 2) as i32)); // Res
-            *stack_size -= 1;
+            // The keys and the values are put onto the stack in pairs. The new map
+            // replaces the base one, or the first key if it's a new map.
+            let first = scope.stack_size;
+            for MapPair{key, value, ..} in pairs.iter() {
+                compile_expr(key, ctx, code, scope)?;
+                compile_expr(value, ctx, code, scope)?;
+            }
+            let dst = if base.is_some() { first - 1 } else { first };
+            let mut src = base_arg;
+            for exact in [false, true] {
+                let list: Vec<Operand> = pairs.iter().enumerate()
+                    .filter(|(_, pair)| pair.exact == exact)
+                    .flat_map(|(index, _)| [scope.arg(first + 2*index), scope.arg(first + 2*index + 1)])
+                    .collect();
+                if list.is_empty() {
+                    continue
+                }
+                // The keys and the values must survive a possible GC as well
+                let live = scope.live(scope.stack_size);
+                code.push(if exact {
+                    Instr::PutMapExact{src, dst: scope.arg(dst), live, pairs: list}
+                } else {
+                    Instr::PutMapAssoc{src, dst: scope.arg(dst), live, pairs: list}
+                });
+                src = scope.arg(dst);
+            }
+            scope.pop(scope.stack_size - dst - 1);
+            Some(())
+        }
+        ExprKind::Binary(segments) => {
+            if segments.is_empty() {
+                let dst = scope.push();
+                code.push(Instr::Move{src: Operand::Literal(ctx.literals.get_id(encode_empty_binary_term())), dst: scope.arg(dst)});
+                return Some(())
+            }
+            // The values and the sizes are put onto the stack. The binary replaces them.
+            let first = scope.stack_size;
+            for Segment{value, size, ..} in segments.iter() {
+                compile_expr(value, ctx, code, scope)?;
+                if let Some(size) = size {
+                    compile_expr(size, ctx, code, scope)?;
+                }
+            }
+            let mut list = Vec::new();
+            let mut slot = first;
+            for (index, Segment{size, spec, ..}) in segments.iter().enumerate() {
+                let value = slot;
+                slot += 1;
+                let size = match size {
+                    Some(_) => {
+                        slot += 1;
+                        scope.arg(slot - 1)
+                    }
+                    None => match spec.default_size() {
+                        Some(size) => Operand::Int(size as i128),
+                        None => Operand::Atom(ctx.atoms.get_id("all")),
+                    }
+                };
+                list.push(segment_type_arg(spec, ctx));
+                list.push(Operand::Unsigned(index + 1)); // Segment number for the error messages
+                list.push(Operand::Unsigned(spec.unit()));
+                list.push(segment_flags_arg(spec, ctx));
+                list.push(scope.arg(value));
+                list.push(size);
+            }
+            code.push(Instr::BsCreateBin{live: scope.live(scope.stack_size), dst: scope.arg(first), segments: list});
+            scope.pop(scope.stack_size - first - 1);
+            Some(())
+        }
+        ExprKind::Record{name, base, fields} => {
+            // The unchanged fields of the update are taken from the base record
+            let base_slot = match base {
+                Some(base) => {
+                    compile_expr(base, ctx, code, scope)?;
+                    let slot = scope.top(1);
+                    emit_record_check(&name.text, slot, ctx, code, scope);
+                    Some(slot)
+                }
+                None => None,
+            };
+
+            let first = scope.push();
+            code.push(Instr::Move{src: Operand::Atom(ctx.atoms.get_id(&name.text)), dst: scope.arg(first)});
+            // The values are computed in the order of the fields in the declaration
+            for (index, field_name) in ctx.records[&name.text].clone().iter().enumerate() {
+                match fields.iter().find(|(field, _)| field.text == *field_name) {
+                    Some((_, value)) => compile_expr(value, ctx, code, scope)?,
+                    None => {
+                        let src = base_slot.expect("the type checker requires all the fields of a new record");
+                        let dst = scope.push();
+                        code.push(Instr::GetTupleElement{src: scope.arg(src), index: index + 1, dst: scope.arg(dst)});
+                    }
+                }
+            }
+            emit_put_tuple(first, code, scope);
+            if let Some(base_slot) = base_slot {
+                code.push(Instr::Move{src: scope.arg(first), dst: scope.arg(base_slot)});
+                scope.pop(1);
+            }
+            Some(())
+        }
+        ExprKind::Field{record, field, record_name} => {
+            compile_expr(record, ctx, code, scope)?;
+            let name = record_name.borrow().clone().expect("the type checker resolves the record");
+            let index = ctx.records[&name].iter().position(|x| *x == field.text).expect("the type checker checks the field");
+            let slot = scope.top(1);
+            emit_record_check(&name, slot, ctx, code, scope);
+            code.push(Instr::GetTupleElement{src: scope.arg(slot), index: index + 1, dst: scope.arg(slot)});
+            Some(())
+        }
+        ExprKind::Binop(Binop{kind, lhs, rhs}) => {
+            compile_expr(lhs, ctx, code, scope)?;
+
+            if let BinopKind::And | BinopKind::Or = kind {
+                // Short-circuit: `lhs and rhs` skips rhs unless lhs is true,
+                // `lhs or rhs` skips rhs unless lhs is false. If rhs is
+                // skipped the value of lhs is the result.
+                let end_label = ctx.new_label();
+                let continue_on = matches!(kind, BinopKind::And);
+                code.push(Instr::Test{op: OpCode::IsEqExact, fail: end_label, args: vec![scope.arg(scope.top(1)), bool_arg(ctx, continue_on)]});
+
+                compile_expr(rhs, ctx, code, scope)?;
+                code.push(Instr::Move{src: scope.arg(scope.top(1)), dst: scope.arg(scope.top(2))});
+                scope.pop(1);
+
+                code.push(Instr::Label(end_label));
+                return Some(())
+            }
+
+            compile_expr(rhs, ctx, code, scope)?;
+
+            let (lhs, rhs, dst) = (scope.arg(scope.top(2)), scope.arg(scope.top(1)), scope.arg(scope.top(2)));
+            if kind.is_comparison() {
+                // Comparisons never allocate, so they don't need the GC version of the BIF
+                let bif = ctx.imports.get_id(&mut ctx.atoms, "erlang", comparison_bif(kind), 2);
+                code.push(Instr::Bif2{fail: scope.fail, bif, lhs, rhs, dst});
+            } else {
+                // The generic arithmetic BIFs handle both integers and floats,
+                // including the mixed cases, so we don't need fadd and friends.
+                let bif2 = match kind {
+                    BinopKind::Sum => ctx.imports.get_id(&mut ctx.atoms, "erlang", "+", 2),
+                    BinopKind::Sub => ctx.imports.get_id(&mut ctx.atoms, "erlang", "-", 2),
+                    BinopKind::Mul => ctx.imports.get_id(&mut ctx.atoms, "erlang", "*", 2),
+                    BinopKind::Div => ctx.imports.get_id(&mut ctx.atoms, "erlang", "/", 2),
+                    _ => unreachable!(),
+                };
+                // Everything below the arguments must survive a possible GC
+                code.push(Instr::GcBif2{fail: scope.fail, live: scope.live(scope.top(2)), bif: bif2, lhs, rhs, dst});
+            }
+            scope.pop(1);
+            Some(())
+        },
+        ExprKind::Not(operand) => {
+            compile_expr(operand, ctx, code, scope)?;
+            let bif = ctx.imports.get_id(&mut ctx.atoms, "erlang", "not", 1);
+            code.push(Instr::Bif1{fail: scope.fail, bif, arg: scope.arg(scope.top(1)), dst: scope.arg(scope.top(1))});
             Some(())
         },
+        ExprKind::Send{pid, msg} => compile_send(pid, msg, ctx, code, scope),
+        ExprKind::Call{name, args} => compile_call(expr, name, args, ctx, code, scope),
+        ExprKind::Case{subject, clauses} => {
+            compile_expr(subject, ctx, code, scope)?;
+            let subject_slot = scope.top(1);
+            let end_label = ctx.new_label();
+            compile_clauses(clauses, subject_slot, subject_slot, end_label, None, ctx, code, scope)?;
+            code.push(Instr::CaseEnd(scope.arg(subject_slot)));
+            code.push(Instr::Label(end_label));
+            Some(())
+        }
+        ExprKind::Receive{clauses, after} => {
+            assert!(scope.frame, "receive clobbers x registers");
+            let dst = scope.push();
+            if let Some(After{timeout, ..}) = after {
+                compile_expr(timeout, ctx, code, scope)?;
+            }
+            let loop_label = ctx.new_label();
+            let wait_label = ctx.new_label();
+            let end_label = ctx.new_label();
+
+            // loop_rec puts the current message into x0 or jumps to wait_label
+            // if there are no more messages in the mailbox
+            code.push(Instr::Label(loop_label));
+            code.push(Instr::LoopRec{fail: wait_label, dst: Operand::X(0)});
+            let msg = scope.push();
+            code.push(Instr::Move{src: Operand::X(0), dst: scope.arg(msg)});
+            compile_clauses(clauses, msg, dst, end_label, Some(Instr::RemoveMessage), ctx, code, scope)?;
+            scope.pop(1);
+
+            // None of the clauses matched, try the next message
+            code.push(Instr::LoopRecEnd(loop_label));
+
+            code.push(Instr::Label(wait_label));
+            match after {
+                Some(After{body, ..}) => {
+                    code.push(Instr::WaitTimeout{label: loop_label, timeout: scope.arg(scope.top(1))});
+                    code.push(Instr::Timeout);
+                    compile_expr(body, ctx, code, scope)?;
+                    code.push(Instr::Move{src: scope.arg(scope.top(1)), dst: scope.arg(dst)});
+                    scope.pop(2);
+                }
+                None => code.push(Instr::Wait(loop_label)),
+            }
+            code.push(Instr::Label(end_label));
+            Some(())
+        }
+        ExprKind::Try{body, clauses, catches} => {
+            assert!(scope.frame, "the catch tag lives in a y register");
+            let dst = scope.push();
+            // While the body is evaluated the slot holds the catch tag
+            // that sends the exceptions to handler_label
+            let tag = scope.push();
+            let handler_label = ctx.new_label();
+            let end_label = ctx.new_label();
+            code.push(Instr::Try{tag: scope.arg(tag), handler: handler_label});
+            compile_expr(body, ctx, code, scope)?;
+            code.push(Instr::TryEnd(scope.arg(tag)));
+
+            // The clauses after `of` are not protected by the catch
+            let value = scope.top(1);
+            if clauses.is_empty() {
+                code.push(Instr::Move{src: scope.arg(value), dst: scope.arg(dst)});
+                code.push(Instr::Jump(end_label));
+            } else {
+                compile_clauses(clauses, value, dst, end_label, None, ctx, code, scope)?;
+                code.push(Instr::TryCaseEnd(scope.arg(value)));
+            }
+            scope.pop(1);
+
+            // try_case puts the class of the exception into x0, the reason
+            // into x1 and the stack trace into x2
+            code.push(Instr::Label(handler_label));
+            code.push(Instr::TryCase(scope.arg(tag)));
+            let class = scope.push();
+            let reason = scope.push();
+            let stacktrace = scope.push();
+            code.push(Instr::Move{src: Operand::X(0), dst: scope.arg(class)});
+            code.push(Instr::Move{src: Operand::X(1), dst: scope.arg(reason)});
+            code.push(Instr::Move{src: Operand::X(2), dst: scope.arg(stacktrace)});
+            for CatchClause{class: class_pattern, clause: MatchClause{pattern, guard, body}} in catches.iter() {
+                let (vars_len, stack_size) = (scope.vars.len(), scope.stack_size);
+                let next_clause_label = ctx.new_label();
+                compile_pattern(class_pattern, class, next_clause_label, ctx, code, scope)?;
+                compile_pattern(pattern, reason, next_clause_label, ctx, code, scope)?;
+                if let Some(guard) = guard {
+                    compile_guard(guard, next_clause_label, ctx, code, scope)?;
+                }
+                compile_expr(body, ctx, code, scope)?;
+                code.push(Instr::Move{src: scope.arg(scope.top(1)), dst: scope.arg(dst)});
+                code.push(Instr::Jump(end_label));
+                code.push(Instr::Label(next_clause_label));
+                scope.vars.truncate(vars_len);
+                scope.stack_size = stack_size;
+            }
+            // None of the clauses caught the exception, so it goes further
+            code.push(Instr::Raise{stacktrace: scope.arg(stacktrace), reason: scope.arg(reason)});
+            scope.pop(4);
+            code.push(Instr::Label(end_label));
+            Some(())
+        }
     }
 }
 
+// Compiles the guard into the test instructions that jump to fail_label
+// when the guard does not hold. Leaves nothing on the stack. Just like in
+// Erlang, the guard that raises an exception does not hold either.
+fn compile_guard(guard: &Expr, fail_label: u32, ctx: &mut Context, code: &mut Vec<Instr>, scope: &mut Scope) -> Option<()> {
+    scope.fail = fail_label;
+    let result = compile_guard_test(guard, fail_label, ctx, code, scope);
+    scope.fail = 0;
+    result
+}
+
+// The exceptions go to scope.fail, which stays the label of the next
+// clause even inside `not` and `or` where fail_label is a different one
+fn compile_guard_test(guard: &Expr, fail_label: u32, ctx: &mut Context, code: &mut Vec<Instr>, scope: &mut Scope) -> Option<()> {
+    match &guard.kind {
+        ExprKind::Binop(Binop{kind: BinopKind::And, lhs, rhs}) => {
+            compile_guard_test(lhs, fail_label, ctx, code, scope)?;
+            compile_guard_test(rhs, fail_label, ctx, code, scope)
+        }
+        ExprKind::Binop(Binop{kind: BinopKind::Or, lhs, rhs}) => {
+            let rhs_label = ctx.new_label();
+            let success_label = ctx.new_label();
+            compile_guard_test(lhs, rhs_label, ctx, code, scope)?;
+            code.push(Instr::Jump(success_label));
+            code.push(Instr::Label(rhs_label));
+            compile_guard_test(rhs, fail_label, ctx, code, scope)?;
+            code.push(Instr::Label(success_label));
+            Some(())
+        }
+        ExprKind::Not(operand) => {
+            let success_label = ctx.new_label();
+            compile_guard_test(operand, success_label, ctx, code, scope)?;
+            code.push(Instr::Jump(fail_label));
+            code.push(Instr::Label(success_label));
+            Some(())
+        }
+        ExprKind::Binop(Binop{kind, lhs, rhs}) if kind.is_comparison() => {
+            compile_expr(lhs, ctx, code, scope)?;
+            compile_expr(rhs, ctx, code, scope)?;
+            let lhs = scope.arg(scope.top(2));
+            let rhs = scope.arg(scope.top(1));
+            // There are no is_gt and is_le, so we swap the arguments of is_lt and is_ge
+            let (op, arg1, arg2) = match kind {
+                BinopKind::Eq => (OpCode::IsEq, lhs, rhs),
+                BinopKind::NotEq => (OpCode::IsNe, lhs, rhs),
+                BinopKind::Less => (OpCode::IsLt, lhs, rhs),
+                BinopKind::GreaterEq => (OpCode::IsGe, lhs, rhs),
+                BinopKind::Greater => (OpCode::IsLt, rhs, lhs),
+                BinopKind::LessEq => (OpCode::IsGe, rhs, lhs),
+                _ => unreachable!(),
+            };
+            code.push(Instr::Test{op, fail: fail_label, args: vec![arg1, arg2]});
+            scope.pop(2);
+            Some(())
+        }
+        _ => {
+            compile_expr(guard, ctx, code, scope)?;
+            code.push(Instr::Test{op: OpCode::IsEqExact, fail: fail_label, args: vec![scope.arg(scope.top(1)), bool_arg(ctx, true)]});
+            scope.pop(1);
+            Some(())
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
 struct CompiledFunc {
+    name_id: u32,
+    func_info_label: u32,
     label: u32,
     arity: u32,
+    exported: bool,
 }
 
 // CodeChunk = <<
@@ -131,49 +981,171 @@ struct CompiledFunc {
 //   Code:(ChunkSize-SubSize)/binary,  % all remaining data
 //   Padding4:0..3/unit:8
 // >>
-fn encode_code_chunk<'a>(module: &'a Module, imports: &HashMap<(u32, u32, u32), u32>, atoms: &mut Atoms, labels: &mut HashMap<u32, CompiledFunc>) -> Vec<u8> {
-    let mut label_count: u32 = 0;
+fn encode_code_chunk(module: &Module, options: &Options, ctx: &mut Context) -> Option<Vec<u8>> {
     let mut function_count: u32 = 0;
 
+    // The labels of all the functions are known upfront, so the calls
+    // can refer to the functions that are compiled later
+    for (name, func) in module.funcs.iter() {
+        let func_info_label = ctx.new_label();
+        let entry_label = ctx.new_label();
+        ctx.funcs.insert(name.clone(), CompiledFunc {
+            name_id: ctx.atoms.get_id(name),
+            func_info_label,
+            label: entry_label,
+            arity: func.arity() as u32,
+            exported: func.exported,
+        });
+    }
+
+    for (name, record) in module.records.iter() {
+        ctx.records.insert(name.clone(), record.fields.iter().map(|field| field.name.text.clone()).collect());
+    }
+    for union in module.unions.values() {
+        for ctor in union.constructors.iter() {
+            ctx.constructors.insert(ctor.name.text.clone(), ctor.fields.len());
+        }
+    }
+
     let mut code = Vec::new();
-    for (_, Func{name, params, body}) in module.funcs.iter() {
+    for (name, func) in module.funcs.iter() {
         function_count += 1;
+        let arity = func.arity();
+        let CompiledFunc{name_id, func_info_label, label: entry_label, ..} = ctx.funcs[name];
 
-        label_count += 1;
-        code.push(OpCode::Label as u8);
-        code.extend(encode_arg(Tag::U, label_count as i32));
-
-        code.push(OpCode::FuncInfo as u8);
-        code.extend(encode_arg(Tag::A, atoms.get_id("bada") as i32));
-        let name_id = atoms.get_id(&name.text);
-        code.extend(encode_arg(Tag::A, name_id as i32));
-        code.extend(encode_arg(Tag::U, params.len() as i32));
-
-        label_count += 1;
-        code.push(OpCode::Label as u8);
-        code.extend(encode_arg(Tag::U, label_count as i32));
-        labels.insert(name_id, CompiledFunc {
-            label: label_count,
-            arity: params.len() as u32,
-        });
+        code.push(Instr::Label(func_info_label));
+        code.push(Instr::FuncInfo{module: ctx.atoms.get_id("bada"), name: name_id, arity});
+        code.push(Instr::Label(entry_label));
+
+        for (i, FuncClause{params, guard, body}) in func.clauses.iter().enumerate() {
+            // If the clause does not match we try the next one. Jumping to the
+            // label of func_info after the last clause raises function_clause.
+            let is_last = i + 1 >= func.clauses.len();
+            let next_clause_label = if is_last { func_info_label } else { ctx.new_label() };
 
-        let mut stack_size = 0;
-        compile_expr(body, atoms, imports, &mut code, &params, &mut stack_size);
+            if options.type_guards {
+                // The patterns check the types of their parameters anyway
+                for (index, param) in params.iter().enumerate().filter(|(_, param)| param.pattern.is_none()) {
+                    if let Type::Record(name) = &param.typ {
+                        code.push(Instr::Test {
+                            op: OpCode::IsTaggedTuple,
+                            fail: next_clause_label,
+                            args: vec![Operand::X(index), Operand::Unsigned(ctx.records[name].len() + 1), Operand::Atom(ctx.atoms.get_id(name))],
+                        });
+                        continue;
+                    }
+                    // The value must be made by one of the constructors
+                    if let Type::Union(name) = &param.typ {
+                        let ok_label = ctx.new_label();
+                        let constructors = &module.unions[name].constructors;
+                        for (i, ctor) in constructors.iter().enumerate() {
+                            let is_last = i + 1 >= constructors.len();
+                            let fail_label = if is_last { next_clause_label } else { ctx.new_label() };
+                            let tag = Operand::Atom(ctx.atoms.get_id(&ctor.name.text));
+                            let (op, args) = if ctor.fields.is_empty() {
+                                (OpCode::IsEqExact, vec![Operand::X(index), tag])
+                            } else {
+                                (OpCode::IsTaggedTuple, vec![Operand::X(index), Operand::Unsigned(ctor.fields.len() + 1), tag])
+                            };
+                            code.push(Instr::Test{op, fail: fail_label, args});
+                            if !is_last {
+                                code.push(Instr::Jump(ok_label));
+                                code.push(Instr::Label(fail_label));
+                            }
+                        }
+                        code.push(Instr::Label(ok_label));
+                        continue;
+                    }
+                    // Only the arities are checked, the elements of the containers are left
+                    // alone, because walking the whole list on each call is too expensive
+                    match &param.typ {
+                        Type::Fun(Some((params, _))) => {
+                            code.push(Instr::Test {
+                                op: OpCode::IsFunction2,
+                                fail: next_clause_label,
+                                args: vec![Operand::X(index), Operand::Int(params.len() as i128)],
+                            });
+                            continue;
+                        }
+                        Type::Tuple(Some(elements)) => {
+                            code.push(Instr::Test{op: OpCode::IsTuple, fail: next_clause_label, args: vec![Operand::X(index)]});
+                            code.push(Instr::Test {
+                                op: OpCode::TestArity,
+                                fail: next_clause_label,
+                                args: vec![Operand::X(index), Operand::Unsigned(elements.len())],
+                            });
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    let op = match param.typ {
+                        Type::Int => OpCode::IsInteger,
+                        Type::Float => OpCode::IsFloat,
+                        Type::Bool => OpCode::IsBoolean,
+                        Type::Atom => OpCode::IsAtom,
+                        Type::Pid => OpCode::IsPid,
+                        Type::Tuple(_) => OpCode::IsTuple,
+                        Type::Fun(_) => OpCode::IsFunction,
+                        Type::Map(_) => OpCode::IsMap,
+                        Type::Binary => OpCode::IsBitstr,
+                        Type::List(_) => OpCode::IsList,
+                        Type::Record(_) | Type::Union(_) | Type::Any | Type::Var(_) => continue,
+                    };
+                    code.push(Instr::Test{op, fail: next_clause_label, args: vec![Operand::X(index)]});
+                }
+            }
+
+            // The patterns and the guard are checked before the stack frame is
+            // allocated, so failing them can just jump to the next clause
+            let mut scope = Scope::new(params);
+            for (index, param) in params.iter().enumerate() {
+                if let Some(pattern) = &param.pattern {
+                    compile_pattern(pattern, index, next_clause_label, ctx, &mut code, &mut scope)?;
+                }
+            }
+            if let Some(guard) = guard {
+                compile_guard(guard, next_clause_label, ctx, &mut code, &mut scope)?;
+            }
+
+            if needs_frame(body, ctx) {
+                // The parameters and the values bound by the patterns move to the frame
+                let live = scope.stack_size;
+                scope.frame = true;
+                scope.max_stack_size = scope.stack_size;
+                let mut body_code = Vec::new();
+                compile_expr(body, ctx, &mut body_code, &mut scope)?;
+                let frame_size = scope.max_stack_size;
 
-        if params.len() > 0 {
-            code.push(OpCode::Move as u8);
-            code.extend(encode_arg(Tag::X, params.len() as i32));
-            code.extend(encode_arg(Tag::X, 0i32));
+                code.push(Instr::AllocateZero{stack_need: frame_size, live});
+                for index in 0..live {
+                    code.push(Instr::Move{src: Operand::X(index), dst: Operand::Y(index)});
+                }
+                code.extend(body_code);
+                code.push(Instr::Move{src: scope.arg(scope.top(1)), dst: Operand::X(0)});
+                code.push(Instr::Deallocate(frame_size));
+            } else {
+                compile_expr(body, ctx, &mut code, &mut scope)?;
+                if scope.top(1) > 0 {
+                    code.push(Instr::Move{src: scope.arg(scope.top(1)), dst: Operand::X(0)});
+                }
+            }
+            code.push(Instr::Return);
+
+            if !is_last {
+                code.push(Instr::Label(next_clause_label));
+            }
         }
-        code.push(OpCode::Return as u8);
     }
-    code.push(OpCode::IntCodeEnd as u8);
+    code.push(Instr::IntCodeEnd);
+    if options.optimize {
+        code = peephole::optimize(code);
+    }
 
-    label_count += 1;
+    let label_count = ctx.new_label();
 
     let sub_size: u32 = 16;
     let instruction_set: u32 = 0;
-    let opcode_max: u32 = 169;
+    let opcode_max: u32 = OpCode::MAX as u32;
 
     let mut chunk = Vec::new();
     chunk.extend(sub_size.to_be_bytes());
@@ -181,9 +1153,9 @@ fn encode_code_chunk<'a>(module: &'a Module, imports: &HashMap<(u32, u32, u32),
     chunk.extend(opcode_max.to_be_bytes());
     chunk.extend(label_count.to_be_bytes());
     chunk.extend(function_count.to_be_bytes());
-    chunk.extend(code);
+    chunk.extend(encode_code(&code));
 
-    encode_chunk(*b"Code", chunk)
+    Some(encode_chunk(*b"Code", chunk))
 }
 
 // AtomChunk = <<
@@ -204,12 +1176,6 @@ fn encode_atom_chunk(atoms: &Atoms) -> Vec<u8> {
     encode_chunk(*b"AtU8", chunk)
 }
 
-fn resolve_function_signature(atoms: &mut Atoms, module: &str, func: &str, arity: u32) -> (u32, u32, u32) {
-    (atoms.get_id(module),
-     atoms.get_id(func),
-     arity)
-}
-
 // ImportChunk = <<
 //   ChunkName:4/unit:8 = "ImpT",
 //   ChunkSize:32/big,
@@ -220,22 +1186,16 @@ fn resolve_function_signature(atoms: &mut Atoms, module: &str, func: &str, arity
 //     >> || repeat ImportCount ],
 //   Padding4:0..3/unit:8
 // >>
-fn encode_imports_chunk(atoms: &mut Atoms, imports: &mut HashMap<(u32, u32, u32), u32>) -> Vec<u8> {
+fn encode_imports_chunk(imports: &Imports) -> Vec<u8> {
     let mut chunk = Vec::new();
-    let import_count: u32 = 2;
+    let import_count: u32 = imports.signatures.len() as u32;
     chunk.extend(import_count.to_be_bytes());
 
-    let (module, func, arity) = resolve_function_signature(atoms, "erlang", "+", 2);
-    chunk.extend(module.to_be_bytes());
-    chunk.extend(func.to_be_bytes());
-    chunk.extend(arity.to_be_bytes());
-    imports.insert((module, func, arity), 0);
-
-    let (module, func, arity) = resolve_function_signature(atoms, "erlang", "-", 2);
-    chunk.extend(module.to_be_bytes());
-    chunk.extend(func.to_be_bytes());
-    chunk.extend(arity.to_be_bytes());
-    imports.insert((module, func, arity), 1);
+    for (module, func, arity) in imports.signatures.iter() {
+        chunk.extend(module.to_be_bytes());
+        chunk.extend(func.to_be_bytes());
+        chunk.extend(arity.to_be_bytes());
+    }
 
     encode_chunk(*b"ImpT", chunk)
 }
@@ -250,12 +1210,12 @@ fn encode_imports_chunk(atoms: &mut Atoms, imports: &mut HashMap<(u32, u32, u32)
 //     >> || repeat ExportCount ],
 //   Padding4:0..3/unit:8
 // >>
-fn encode_exports_chunk(labels: &HashMap<u32, CompiledFunc>) -> Vec<u8> {
+fn encode_exports_chunk(funcs: &BTreeMap<String, CompiledFunc>) -> Vec<u8> {
     let mut chunk = Vec::new();
-    let export_count: u32 = labels.len() as u32;
+    let export_count: u32 = funcs.values().filter(|func| func.exported).count() as u32;
     chunk.extend(export_count.to_be_bytes());
 
-    for (name_id, CompiledFunc{label, arity}) in labels.iter() {
+    for (_, CompiledFunc{name_id, label, arity, ..}) in funcs.iter().filter(|(_, func)| func.exported) {
         chunk.extend(name_id.to_be_bytes());
         chunk.extend(arity.to_be_bytes());
         chunk.extend(label.to_be_bytes());
@@ -264,6 +1224,33 @@ fn encode_exports_chunk(labels: &HashMap<u32, CompiledFunc>) -> Vec<u8> {
     encode_chunk(*b"ExpT", chunk)
 }
 
+// FunTableChunk = <<
+//   ChunkName:4/unit:8 = "FunT",
+//   ChunkSize:32/big,
+//   FunCount:32/big,
+//   [ << FunctionName:32/big,
+//        Arity:32/big,
+//        Label:32/big,
+//        Index:32/big,
+//        NumFree:32/big,
+//        OldUniq:32/big
+//     >> || repeat FunCount ],
+//   Padding4:0..3/unit:8
+// >>
+fn encode_fun_table_chunk(lambdas: &Lambdas) -> Vec<u8> {
+    let mut chunk = Vec::new();
+    chunk.extend((lambdas.entries.len() as u32).to_be_bytes());
+    for (index, (name_id, arity, label)) in lambdas.entries.iter().enumerate() {
+        chunk.extend(name_id.to_be_bytes());
+        chunk.extend(arity.to_be_bytes());
+        chunk.extend(label.to_be_bytes());
+        chunk.extend((index as u32).to_be_bytes());
+        chunk.extend(0u32.to_be_bytes()); // NumFree
+        chunk.extend(0u32.to_be_bytes()); // OldUniq
+    }
+    encode_chunk(*b"FunT", chunk)
+}
+
 // StringChunk = <<
 //   ChunkName:4/unit:8 = "StrT",
 //   ChunkSize:32/big,
@@ -274,6 +1261,151 @@ fn encode_string_chunk() -> Vec<u8> {
     encode_chunk(*b"StrT", vec![])
 }
 
+// LiteralChunk = <<
+//   ChunkName:4/unit:8 = "LitT",
+//   ChunkSize:32/big,
+//   UncompressedSize:32/big,
+//   zlib:compress(<<
+//     NumberOfLiterals:32/big,
+//     [<<LiteralSize:32/big, term_to_binary(Literal):LiteralSize/binary>> || repeat NumberOfLiterals]
+//   >>),
+//   Padding4:0..3/unit:8
+// >>
+fn encode_literal_chunk(literals: &Literals) -> Vec<u8> {
+    let mut table = Vec::new();
+    table.extend((literals.terms.len() as u32).to_be_bytes());
+    for term in literals.terms.iter() {
+        table.extend((term.len() as u32).to_be_bytes());
+        table.extend(term);
+    }
+
+    let mut chunk = Vec::new();
+    chunk.extend((table.len() as u32).to_be_bytes());
+    chunk.extend(zlib_stored(&table));
+    encode_chunk(*b"LitT", chunk)
+}
+
+// The loader always inflates the literal table, but nobody forces us to actually
+// compress it. A zlib stream made of "stored" deflate blocks is good enough.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK_SIZE: usize = 0xFFFF;
+    let mut result = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        result.extend([1, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none() as u8;
+        let len = block.len() as u16;
+        result.push(is_final);
+        result.extend(len.to_le_bytes());
+        result.extend((!len).to_le_bytes());
+        result.extend(block);
+    }
+
+    // Adler-32
+    let (mut a, mut b) = (1u32, 0u32);
+    for x in data {
+        a = (a + *x as u32)%65521;
+        b = (b + a)%65521;
+    }
+    result.extend(((b<<16)|a).to_be_bytes());
+    result
+}
+
+const VERSION_MAGIC: u8 = 131;
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_TUPLE_EXT: u8 = 104;
+const NIL_EXT: u8 = 106;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const MAP_EXT: u8 = 116;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+fn encode_empty_tuple_term() -> Vec<u8> {
+    vec![VERSION_MAGIC, SMALL_TUPLE_EXT, 0]
+}
+
+fn encode_empty_binary_term() -> Vec<u8> {
+    vec![VERSION_MAGIC, BINARY_EXT, 0, 0, 0, 0]
+}
+
+fn encode_atom_list_term(atoms: &[&str]) -> Vec<u8> {
+    let mut term = vec![VERSION_MAGIC, LIST_EXT];
+    term.extend((atoms.len() as u32).to_be_bytes());
+    for atom in atoms {
+        term.push(SMALL_ATOM_UTF8_EXT);
+        term.push(atom.len() as u8);
+        term.extend(atom.as_bytes());
+    }
+    term.push(NIL_EXT);
+    term
+}
+
+fn encode_empty_map_term() -> Vec<u8> {
+    vec![VERSION_MAGIC, MAP_EXT, 0, 0, 0, 0]
+}
+
+fn encode_float_term(x: f64) -> Vec<u8> {
+    let mut term = vec![VERSION_MAGIC, NEW_FLOAT_EXT];
+    term.extend(x.to_bits().to_be_bytes());
+    term
+}
+
+#[derive(Default)]
+struct Literals {
+    terms: Vec<Vec<u8>>,
+}
+
+impl Literals {
+    // Unlike atoms, literals are indexed from 0
+    fn get_id(&mut self, term: Vec<u8>) -> u32 {
+        if let Some(index) = self.terms.iter().position(|x| *x == term) {
+            index as u32
+        } else {
+            self.terms.push(term);
+            (self.terms.len() - 1) as u32
+        }
+    }
+}
+
+// The funs referring to the functions of the module. They don't capture anything yet.
+#[derive(Default)]
+struct Lambdas {
+    entries: Vec<(u32, u32, u32)>,
+}
+
+impl Lambdas {
+    // Lambdas are indexed from 0
+    fn get_id(&mut self, name_id: u32, arity: u32, label: u32) -> u32 {
+        let entry = (name_id, arity, label);
+        if let Some(index) = self.entries.iter().position(|x| *x == entry) {
+            index as u32
+        } else {
+            self.entries.push(entry);
+            (self.entries.len() - 1) as u32
+        }
+    }
+}
+
+#[derive(Default)]
+struct Imports {
+    signatures: Vec<(u32, u32, u32)>,
+}
+
+impl Imports {
+    // Imports are indexed from 0
+    fn get_id(&mut self, atoms: &mut Atoms, module: &str, func: &str, arity: u32) -> u32 {
+        let signature = (atoms.get_id(module), atoms.get_id(func), arity);
+        if let Some(index) = self.signatures.iter().position(|x| *x == signature) {
+            index as u32
+        } else {
+            self.signatures.push(signature);
+            (self.signatures.len() - 1) as u32
+        }
+    }
+}
+
 #[derive(Default)]
 struct Atoms {
     names: Vec<String>,
@@ -295,20 +1427,69 @@ impl Atoms {
     }
 }
 
-pub fn compile_beam_module(module: &Module) -> Vec<u8> {
-    let mut atoms = Atoms::default();
-    let mut labels: HashMap<u32, CompiledFunc> = HashMap::new();
-    let mut imports: HashMap<(u32, u32, u32), u32> = HashMap::new();
+pub fn compile_beam_module(module: &Module, options: &Options) -> Option<Vec<u8>> {
+    let mut ctx = Context::default();
 
     // TODO: get module name from the stem of the input file
-    let _ = atoms.get_id("bada");
+    let _ = ctx.atoms.get_id("bada");
+
+    // The Code chunk is encoded first, because it populates the rest of the tables
+    let code_chunk = encode_code_chunk(module, options, &mut ctx)?;
 
     let mut beam = Vec::new();
     beam.extend("BEAM".as_bytes());
-    beam.extend(encode_imports_chunk(&mut atoms, &mut imports));
-    beam.extend(encode_code_chunk(&module, &imports, &mut atoms, &mut labels));
-    beam.extend(encode_exports_chunk(&labels));
+    beam.extend(encode_imports_chunk(&ctx.imports));
+    beam.extend(code_chunk);
+    beam.extend(encode_exports_chunk(&ctx.funcs));
+    if !ctx.literals.terms.is_empty() {
+        beam.extend(encode_literal_chunk(&ctx.literals));
+    }
+    if !ctx.lambdas.entries.is_empty() {
+        beam.extend(encode_fun_table_chunk(&ctx.lambdas));
+    }
     beam.extend(encode_string_chunk());
-    beam.extend(encode_atom_chunk(&atoms));
-    beam
+    beam.extend(encode_atom_chunk(&ctx.atoms));
+    Some(beam)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diag;
+    use lex::Lexer;
+
+    // Skips the type checker, so the code generator sees the errors it would catch
+    fn compile_unchecked(source: &str) -> (Option<Vec<u8>>, Vec<&'static str>) {
+        let content: Vec<char> = source.chars().collect();
+        let mut lexer = Lexer::new(&content, "test.boom".to_string());
+        let module = Module::parse(&mut lexer).expect("the module parses");
+        let beam = compile_beam_module(&module, &Options::default());
+        let codes = diag::take_diagnostics().reports.iter().map(|diagnostic| diagnostic.code.id).collect();
+        (beam, codes)
+    }
+
+    #[test]
+    fn failed_bodies() {
+        // With and without the stack frame
+        for (source, code) in [("f(x int) = missing(x);\n", "E0004"), ("f(x int) = x + y;\n", "E0003")] {
+            let (beam, codes) = compile_unchecked(source);
+            assert!(beam.is_none(), "{}", source);
+            assert_eq!(codes, [code]);
+        }
+    }
+
+    #[test]
+    fn failed_guards() {
+        let (beam, codes) = compile_unchecked("f(x int) when x > y = 1;\nf(x int) = 0;\n");
+        assert!(beam.is_none());
+        assert_eq!(codes, ["E0003"]);
+    }
+
+    #[test]
+    fn oversized_binary_patterns() {
+        // Each of the segments fits, but not both of them
+        let (beam, codes) = compile_unchecked("f(<<a:288230376151711744/bits, b:288230376151711744/bits>>) = 1;\n");
+        assert!(beam.is_none());
+        assert_eq!(codes, ["E0028"]);
+    }
 }
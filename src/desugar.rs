@@ -0,0 +1,220 @@
+use diag::*;
+use lex::{Token, TokenKind};
+use parser::{Expr, ExprKind, Module, Func, FuncClause, Binop, BinopKind, Param, Params, Type, Pattern, PatternKind, MatchClause, After};
+
+// The comprehension `[body || pattern <- list, filter, ...]` becomes the call of
+// the hidden recursive function that captures the variables it refers to:
+//
+//   -f/1-lc$0-(#list, captured...) = case #list of
+//       [] -> [];
+//       [pattern | #rest] -> case filter and ... of
+//           true -> [body | -f/1-lc$0-(#rest, captured...)];
+//           false -> -f/1-lc$0-(#rest, captured...)
+//       end;
+//       [_ | #rest] -> -f/1-lc$0-(#rest, captured...)
+//   end;
+//
+// The names of the hidden functions and their parameters are not valid
+// identifiers, so they never clash with anything in the source code.
+struct Desugar {
+    func_name: String,
+    arity: usize,
+    count: usize,
+    hidden_funcs: Vec<Func>,
+}
+
+fn ident(text: &str, loc: &Loc) -> Token {
+    Token {
+        kind: TokenKind::Ident,
+        text: text.to_string(),
+        loc: loc.clone(),
+        end: loc.clone(),
+    }
+}
+
+fn var(text: &str, loc: &Loc) -> Expr {
+    Expr {loc: loc.clone(), end: loc.clone(), kind: ExprKind::Var(ident(text, loc))}
+}
+
+// The variables the expression refers to, including the ones in its patterns
+// and the called funs
+fn mentioned_vars(expr: &Expr, vars: &mut Vec<String>) {
+    if let ExprKind::Var(name) | ExprKind::Call{name, ..} = &expr.kind {
+        if !vars.contains(&name.text) {
+            vars.push(name.text.clone());
+        }
+    }
+    for pattern in expr.patterns() {
+        pattern.vars(vars);
+    }
+    for child in expr.children() {
+        mentioned_vars(child, vars);
+    }
+}
+
+fn desugar_comprehension(expr: &mut Expr, bound: &mut Vec<String>, desugar: &mut Desugar) {
+    let (loc, end) = (expr.loc.clone(), expr.end.clone());
+    let (mut body, pattern, mut list, mut filters) = match std::mem::replace(&mut expr.kind, ExprKind::Tuple(vec![])) {
+        ExprKind::Comprehension{body, pattern, list, filters} => (body, pattern, list, filters),
+        _ => unreachable!(),
+    };
+
+    desugar_expr(&mut list, bound, desugar);
+    let bound_len = bound.len();
+    pattern.vars(bound);
+    desugar_expr(&mut body, bound, desugar);
+    for filter in filters.iter_mut() {
+        desugar_expr(filter, bound, desugar);
+    }
+    bound.truncate(bound_len);
+
+    // Since the variables are never shadowed, everything the comprehension
+    // mentions that is bound outside of it must be captured
+    let mut mentioned = Vec::new();
+    pattern.vars(&mut mentioned);
+    mentioned_vars(&body, &mut mentioned);
+    for filter in filters.iter() {
+        mentioned_vars(filter, &mut mentioned);
+    }
+    let captured: Vec<String> = mentioned.into_iter().filter(|name| bound.contains(name)).collect();
+
+    let name = format!("-{func}/{arity}-lc${count}-", func = desugar.func_name, arity = desugar.arity, count = desugar.count);
+    desugar.count += 1;
+    let call = |list: Expr| {
+        let mut args = vec![list];
+        args.extend(captured.iter().map(|name| var(name, &loc)));
+        Expr {loc: loc.clone(), end: end.clone(), kind: ExprKind::Call {name: ident(&name, &loc), args}}
+    };
+
+    let catch_all = !matches!(pattern.kind, PatternKind::Var(_) | PatternKind::Wildcard);
+    let cons = Expr {loc: loc.clone(), end: end.clone(), kind: ExprKind::List {elements: vec![*body], tail: Some(Box::new(call(var("#rest", &loc))))}};
+    let head_body = match filters.into_iter().reduce(|lhs, rhs| {
+        Expr {loc: lhs.loc.clone(), end: rhs.end.clone(), kind: ExprKind::Binop(Binop {kind: BinopKind::And, lhs: Box::new(lhs), rhs: Box::new(rhs)})}
+    }) {
+        Some(condition) => Expr {loc: loc.clone(), end: end.clone(), kind: ExprKind::Case {
+            subject: Box::new(condition),
+            clauses: vec![
+                MatchClause {pattern: Pattern {loc: loc.clone(), end: end.clone(), kind: PatternKind::Bool(true)}, guard: None, body: cons},
+                MatchClause {pattern: Pattern {loc: loc.clone(), end: end.clone(), kind: PatternKind::Bool(false)}, guard: None, body: call(var("#rest", &loc))},
+            ],
+        }},
+        None => cons,
+    };
+
+    let rest_pattern = || Some(Box::new(Pattern {loc: loc.clone(), end: end.clone(), kind: PatternKind::Var(ident("#rest", &loc))}));
+    let mut clauses = vec![
+        MatchClause {
+            pattern: Pattern {loc: loc.clone(), end: end.clone(), kind: PatternKind::List {elements: vec![], tail: None}},
+            guard: None,
+            body: Expr {loc: loc.clone(), end: end.clone(), kind: ExprKind::List {elements: vec![], tail: None}},
+        },
+        MatchClause {
+            pattern: Pattern {loc: loc.clone(), end: end.clone(), kind: PatternKind::List {elements: vec![pattern], tail: rest_pattern()}},
+            guard: None,
+            body: head_body,
+        },
+    ];
+    // The elements that don't match the pattern are skipped
+    if catch_all {
+        clauses.push(MatchClause {
+            pattern: Pattern {loc: loc.clone(), end: end.clone(), kind: PatternKind::List {elements: vec![Pattern {loc: loc.clone(), end: end.clone(), kind: PatternKind::Wildcard}], tail: rest_pattern()}},
+            guard: None,
+            body: call(var("#rest", &loc)),
+        });
+    }
+
+    // The types of the parameters are left for the type checker to infer
+    let mut params = Params::default();
+    let element = Type::Var("#element".to_string());
+    params.push(Param {name: ident("#list", &loc), typ: Type::List(Some(Box::new(element))), pattern: None});
+    for name in captured.iter() {
+        params.push(Param {name: ident(name, &loc), typ: Type::Var(name.clone()), pattern: None});
+    }
+    let body = Expr {loc: loc.clone(), end: end.clone(), kind: ExprKind::Case {subject: Box::new(var("#list", &loc)), clauses}};
+    desugar.hidden_funcs.push(Func {
+        name: ident(&name, &loc),
+        clauses: vec![FuncClause {params, guard: None, body}],
+        exported: false,
+    });
+
+    *expr = call(*list);
+}
+
+// Comprehensions are not allowed in guards, so those are left for the type checker to report
+fn desugar_clause(MatchClause{pattern, body, ..}: &mut MatchClause, bound: &mut Vec<String>, desugar: &mut Desugar) {
+    let bound_len = bound.len();
+    pattern.vars(bound);
+    desugar_expr(body, bound, desugar);
+    bound.truncate(bound_len);
+}
+
+// `bound` are the variables that are in the scope of the expression
+fn desugar_expr(expr: &mut Expr, bound: &mut Vec<String>, desugar: &mut Desugar) {
+    match &mut expr.kind {
+        ExprKind::Comprehension{..} => desugar_comprehension(expr, bound, desugar),
+        ExprKind::Case{subject, clauses} => {
+            desugar_expr(subject, bound, desugar);
+            for clause in clauses.iter_mut() {
+                desugar_clause(clause, bound, desugar);
+            }
+        }
+        ExprKind::Receive{clauses, after} => {
+            for clause in clauses.iter_mut() {
+                desugar_clause(clause, bound, desugar);
+            }
+            if let Some(After{timeout, body}) = after {
+                desugar_expr(timeout, bound, desugar);
+                desugar_expr(body, bound, desugar);
+            }
+        }
+        ExprKind::Try{body, clauses, catches} => {
+            desugar_expr(body, bound, desugar);
+            for clause in clauses.iter_mut() {
+                desugar_clause(clause, bound, desugar);
+            }
+            for catch in catches.iter_mut() {
+                let bound_len = bound.len();
+                catch.class.vars(bound);
+                desugar_clause(&mut catch.clause, bound, desugar);
+                bound.truncate(bound_len);
+            }
+        }
+        _ => {
+            for child in expr.children_mut() {
+                desugar_expr(child, bound, desugar);
+            }
+        }
+    }
+}
+
+// Replaces the constructs that are compiled through the others
+pub fn desugar_module(module: &mut Module) {
+    let mut desugar = Desugar {
+        func_name: String::new(),
+        arity: 0,
+        count: 0,
+        hidden_funcs: Vec::new(),
+    };
+    for (name, func) in module.funcs.iter_mut() {
+        desugar.func_name = name.clone();
+        desugar.arity = func.arity();
+        for FuncClause{params, body, ..} in func.clauses.iter_mut() {
+            let mut bound: Vec<String> = params.names().cloned().collect();
+            for param in params.iter() {
+                if let Some(pattern) = &param.pattern {
+                    pattern.vars(&mut bound);
+                }
+            }
+            desugar_expr(body, &mut bound, &mut desugar);
+        }
+    }
+    // The comprehensions are never known at compile time, but that's for the constant folding to report
+    for (name, decl) in module.consts.iter_mut() {
+        desugar.func_name = name.clone();
+        desugar.arity = 0;
+        desugar_expr(&mut decl.value, &mut Vec::new(), &mut desugar);
+    }
+    for func in desugar.hidden_funcs {
+        module.funcs.insert(func.name.text.clone(), func);
+    }
+}
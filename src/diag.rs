@@ -1,20 +1,213 @@
+use std::cell::RefCell;
+use codes::Code;
+
+#[derive(Clone)]
 pub struct Loc {
     pub file_path: String,
     pub row: usize,
     pub col: usize,
 }
 
+// The end is right after the last character. The span of a bare location is
+// empty, and it is underlined by a single caret.
+#[derive(Clone)]
+pub struct Span {
+    pub start: Loc,
+    pub end: Loc,
+}
+
+// Anything the diagnostics can point at
+pub trait ToSpan {
+    fn to_span(&self) -> Span;
+}
+
+impl ToSpan for Loc {
+    fn to_span(&self) -> Span {
+        Span {start: self.clone(), end: self.clone()}
+    }
+}
+
+impl ToSpan for Span {
+    fn to_span(&self) -> Span {
+        self.clone()
+    }
+}
+
+impl<T: ToSpan + ?Sized> ToSpan for &T {
+    fn to_span(&self) -> Span {
+        (**self).to_span()
+    }
+}
+
+// The other place in the code the diagnostic refers to, like the existing
+// definition of the redefined function
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+pub struct Diagnostic {
+    pub span: Span,
+    pub level: &'static str,
+    pub code: &'static Code,
+    pub message: String,
+    pub labels: Vec<Label>,
+}
+
+pub enum Format {
+    // For the people, with the quoted source
+    Human,
+    // For the editors and the bots
+    Json,
+}
+
+// Everything reported while compiling the module. The stages keep going after
+// the errors they can recover from, so the user sees all of them at once.
+#[derive(Default)]
+pub struct Diagnostics {
+    pub reports: Vec<Diagnostic>,
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+// The line of the source with the span underlined by `mark`s. The span that
+// goes on to the next lines is underlined up to the end of its first line.
+fn print_snippet(source: &[char], span: &Span, mark: char, message: &str, gutter: usize, mark_colour: &str, colour: bool) {
+    let line: Vec<char> = match source.split(|x| *x == '\n').nth(span.start.row - 1) {
+        Some(line) => line.to_vec(),
+        None => return,
+    };
+    let (blue, reset) = if colour { (BLUE, RESET) } else { ("", "") };
+    let start = span.start.col - 1;
+    let end = if span.end.row == span.start.row { span.end.col - 1 } else { line.len() };
+    // The tabs are kept, so the marks line up with the text above them
+    let padding: String = line.iter().take(start).map(|x| if *x == '\t' { '\t' } else { ' ' }).collect();
+    let marks: String = std::iter::repeat_n(mark, end.saturating_sub(start).max(1)).collect();
+    let line: String = line.into_iter().collect();
+    let row = span.start.row;
+    eprintln!("{blue}{row:>gutter$} |{reset} {line}");
+    let message = if message.is_empty() { String::new() } else { format!(" {message}") };
+    eprintln!("{blue}{empty:>gutter$} |{reset} {padding}{mark_colour}{marks}{message}{reset}", empty = "");
+}
+
+impl Diagnostics {
+    pub fn push(&mut self, span: Span, level: &'static str, code: &'static Code, message: String) {
+        self.reports.push(Diagnostic {span, level, code, message, labels: Vec::new()});
+    }
+
+    // Attaches the label to the last diagnostic
+    pub fn label(&mut self, span: Span, message: String) {
+        let diagnostic = self.reports.last_mut().expect("the label follows its diagnostic");
+        diagnostic.labels.push(Label {span, message});
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.reports.iter().filter(|diagnostic| diagnostic.level == "ERROR").count()
+    }
+
+    // One JSON object per line for the tools that read the diagnostics:
+    //
+    // {"file":"bada.boom","line":13,"column":1,"end_line":13,"end_column":2,"severity":"error","code":"E0009",
+    //  "message":"...","related":[{"file":"bada.boom","line":12,"column":1,...,"message":"..."}]}
+    pub fn json_lines(&self) -> Vec<String> {
+        self.reports.iter().map(|Diagnostic{span, level, code, message, labels}| {
+            let related: Vec<String> = labels.iter()
+                .map(|Label{span, message}| format!("{{{span},\"message\":{message}}}", span = json_span(span), message = json_string(message)))
+                .collect();
+            format!("{{{span},\"severity\":{severity},\"code\":{code},\"message\":{message},\"related\":[{related}]}}",
+                    span = json_span(span), severity = json_string(&level.to_lowercase()), code = json_string(code.id), message = json_string(message), related = related.join(","))
+        }).collect()
+    }
+
+    pub fn print_json(&self) {
+        for line in self.json_lines() {
+            eprintln!("{line}");
+        }
+    }
+
+    // The source is the content of the compiled file, which all the
+    // diagnostics point into
+    pub fn print(&self, source: &[char], colour: bool) {
+        let reset = if colour { RESET } else { "" };
+        for Diagnostic{span, level, code, message, labels} in self.reports.iter() {
+            let Loc{file_path, row, col} = &span.start;
+            let level_colour = match *level {
+                _ if !colour => "",
+                "ERROR" => RED,
+                "WARNING" => YELLOW,
+                _ => BOLD,
+            };
+            let bold = if colour { BOLD } else { "" };
+            eprintln!("{bold}{file_path}:{row}:{col}:{reset} {level_colour}{level}[{id}]:{reset} {bold}{message}{reset}", id = code.id);
+
+            let gutter = std::iter::once(span).chain(labels.iter().map(|label| &label.span))
+                .map(|span| span.start.row.to_string().len())
+                .max()
+                .unwrap_or(1);
+            print_snippet(source, span, '^', "", gutter, level_colour, colour);
+            for Label{span, message} in labels.iter() {
+                print_snippet(source, span, '-', message, gutter, if colour { BLUE } else { "" }, colour);
+            }
+        }
+    }
+}
+
+// The string literal of JSON
+fn json_string(text: &str) -> String {
+    let mut result = String::from("\"");
+    for x in text.chars() {
+        match x {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            x if x.is_control() => result.push_str(&format!("\\u{code:04x}", code = x as u32)),
+            x => result.push(x),
+        }
+    }
+    result.push('"');
+    result
+}
+
+// The fields of the span in the JSON object. The lines and the columns start
+// from 1, and the end points right after the last character.
+fn json_span(Span{start, end}: &Span) -> String {
+    format!("\"file\":{file},\"line\":{line},\"column\":{column},\"end_line\":{end_line},\"end_column\":{end_column}",
+            file = json_string(&start.file_path), line = start.row, column = start.col, end_line = end.row, end_column = end.col)
+}
+
+thread_local! {
+    // The sink of report!, so the diagnostics don't have to be passed through every stage
+    pub static DIAGNOSTICS: RefCell<Diagnostics> = RefCell::new(Diagnostics::default());
+}
+
+// Takes everything reported so far out of the sink. The stages go through
+// the definitions in the order of their names, so the diagnostics are put
+// back into the order of the source.
+pub fn take_diagnostics() -> Diagnostics {
+    let mut diagnostics = DIAGNOSTICS.with(|diagnostics| diagnostics.take());
+    diagnostics.reports.sort_by_key(|diagnostic| (diagnostic.span.start.row, diagnostic.span.start.col));
+    diagnostics
+}
+
+// The code is the name of the constant of the codes module, like E0003
 macro_rules! report {
-    ($loc:expr, $level:literal, $fmt:literal) => {
-        let Loc{file_path, row, col} = $loc;
-        let level = $level;
-        eprint!("{file_path}:{row}:{col}: {level}: ");
-        eprintln!($fmt);
+    ($span:expr, $level:literal, $code:ident, $($fmt:tt)*) => {
+        let span = $crate::diag::ToSpan::to_span(&$span);
+        let message = format!($($fmt)*);
+        $crate::diag::DIAGNOSTICS.with(|diagnostics| diagnostics.borrow_mut().push(span, $level, &$crate::codes::$code, message));
     };
-    ($loc:expr, $level:literal, $fmt:literal, $($args:tt)*) => {
-        let Loc{file_path, row, col} = $loc;
-        let level = $level;
-        eprint!("{file_path}:{row}:{col}: {level}: ");
-        eprintln!($fmt, $($args)*);
+}
+
+// The secondary label of the diagnostic reported right before it
+macro_rules! label {
+    ($span:expr, $($fmt:tt)*) => {
+        let span = $crate::diag::ToSpan::to_span(&$span);
+        let message = format!($($fmt)*);
+        $crate::diag::DIAGNOSTICS.with(|diagnostics| diagnostics.borrow_mut().label(span, message));
     };
 }
@@ -0,0 +1,217 @@
+use parser::{Expr, ExprKind, Module, Const, FuncClause, Binop, BinopKind, MatchClause, After};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+// Computes the expressions whose operands are known at compile time and
+// replaces the uses of the constants with their values. The results must be
+// the same as the ones computed at run time, so anything that would raise
+// there, like the division by zero, is left alone.
+struct Fold {
+    // The constants that are not folded yet
+    pending: BTreeMap<String, Const>,
+    folded: BTreeMap<String, Const>,
+    ok: bool,
+}
+
+// The values of the constants may be put anywhere in the code. The operations
+// that could not be folded, like the ones overflowing i128, are computed at
+// run time wherever the constant is used.
+fn is_constant(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Atom(_) => true,
+        ExprKind::Binop(Binop{lhs, rhs, ..}) => is_constant(lhs) && is_constant(rhs),
+        ExprKind::Not(operand) => is_constant(operand),
+        ExprKind::Tuple(elements) => elements.iter().all(is_constant),
+        ExprKind::List{elements, tail} => elements.iter().chain(tail.iter().map(|tail| &**tail)).all(is_constant),
+        _ => false,
+    }
+}
+
+fn copy_constant(expr: &Expr) -> Expr {
+    let kind = match &expr.kind {
+        ExprKind::Number(x) => ExprKind::Number(*x),
+        ExprKind::Float(x) => ExprKind::Float(*x),
+        ExprKind::Bool(x) => ExprKind::Bool(*x),
+        ExprKind::Atom(name) => ExprKind::Atom(name.clone()),
+        ExprKind::Binop(Binop{kind, lhs, rhs}) => ExprKind::Binop(Binop {
+            kind: kind.clone(),
+            lhs: Box::new(copy_constant(lhs)),
+            rhs: Box::new(copy_constant(rhs)),
+        }),
+        ExprKind::Not(operand) => ExprKind::Not(Box::new(copy_constant(operand))),
+        ExprKind::Tuple(elements) => ExprKind::Tuple(elements.iter().map(copy_constant).collect()),
+        ExprKind::List{elements, tail} => ExprKind::List {
+            elements: elements.iter().map(copy_constant).collect(),
+            tail: tail.as_ref().map(|tail| Box::new(copy_constant(tail))),
+        },
+        _ => unreachable!("not a constant"),
+    };
+    Expr {loc: expr.loc.clone(), end: expr.end.clone(), kind}
+}
+
+impl Fold {
+    // The constants are folded on their first use. The type checker makes
+    // sure they don't refer to themselves.
+    fn value_of(&mut self, name: &str) -> Option<Expr> {
+        if let Some(mut decl) = self.pending.remove(name) {
+            fold_expr(&mut decl.value, &mut Vec::new(), self);
+            if !is_constant(&decl.value) {
+                report!(&decl.value, "ERROR", E0027, "The value of constant {name} is not known at compile time");
+                self.ok = false;
+                return None
+            }
+            self.folded.insert(name.to_string(), decl);
+        }
+        self.folded.get(name).map(|decl| copy_constant(&decl.value))
+    }
+}
+
+fn as_float(kind: &ExprKind) -> Option<f64> {
+    match kind {
+        ExprKind::Number(x) => Some(*x as f64),
+        ExprKind::Float(x) => Some(*x),
+        _ => None,
+    }
+}
+
+// Integers are compared with floats exactly only while they fit the mantissa
+fn compare_numbers(lhs: &ExprKind, rhs: &ExprKind) -> Option<Ordering> {
+    const EXACT: i128 = 1 << 53;
+    match (lhs, rhs) {
+        (ExprKind::Number(a), ExprKind::Number(b)) => Some(a.cmp(b)),
+        (ExprKind::Number(x), _) | (_, ExprKind::Number(x)) if x.abs() > EXACT => None,
+        _ => as_float(lhs)?.partial_cmp(&as_float(rhs)?),
+    }
+}
+
+fn fold_binop(kind: &BinopKind, lhs: &ExprKind, rhs: &ExprKind) -> Option<ExprKind> {
+    match (kind, lhs, rhs) {
+        // The integers of Erlang never overflow, so the results that don't fit
+        // i128 are left for the run time to compute
+        (BinopKind::Sum, ExprKind::Number(a), ExprKind::Number(b)) => a.checked_add(*b).map(ExprKind::Number),
+        (BinopKind::Sub, ExprKind::Number(a), ExprKind::Number(b)) => a.checked_sub(*b).map(ExprKind::Number),
+        (BinopKind::Mul, ExprKind::Number(a), ExprKind::Number(b)) => a.checked_mul(*b).map(ExprKind::Number),
+        (BinopKind::Sum | BinopKind::Sub | BinopKind::Mul | BinopKind::Div, _, _) => {
+            let (a, b) = (as_float(lhs)?, as_float(rhs)?);
+            let result = match kind {
+                BinopKind::Sum => a + b,
+                BinopKind::Sub => a - b,
+                BinopKind::Mul => a * b,
+                _ => a / b,
+            };
+            // badarith instead of infinities and NaNs
+            if result.is_finite() { Some(ExprKind::Float(result)) } else { None }
+        }
+        (BinopKind::And, ExprKind::Bool(a), ExprKind::Bool(b)) => Some(ExprKind::Bool(*a && *b)),
+        (BinopKind::Or, ExprKind::Bool(a), ExprKind::Bool(b)) => Some(ExprKind::Bool(*a || *b)),
+        (BinopKind::Eq | BinopKind::NotEq, _, _) => {
+            let equal = match (lhs, rhs) {
+                (ExprKind::Atom(a), ExprKind::Atom(b)) => a == b,
+                (ExprKind::Bool(a), ExprKind::Bool(b)) => a == b,
+                _ => compare_numbers(lhs, rhs)? == Ordering::Equal,
+            };
+            Some(ExprKind::Bool(equal == matches!(kind, BinopKind::Eq)))
+        }
+        (BinopKind::Less, _, _) => Some(ExprKind::Bool(compare_numbers(lhs, rhs)?.is_lt())),
+        (BinopKind::LessEq, _, _) => Some(ExprKind::Bool(compare_numbers(lhs, rhs)?.is_le())),
+        (BinopKind::Greater, _, _) => Some(ExprKind::Bool(compare_numbers(lhs, rhs)?.is_gt())),
+        (BinopKind::GreaterEq, _, _) => Some(ExprKind::Bool(compare_numbers(lhs, rhs)?.is_ge())),
+        _ => None,
+    }
+}
+
+fn fold_clause(MatchClause{pattern, guard, body}: &mut MatchClause, bound: &mut Vec<String>, fold: &mut Fold) {
+    let bound_len = bound.len();
+    pattern.vars(bound);
+    if let Some(guard) = guard {
+        fold_expr(guard, bound, fold);
+    }
+    fold_expr(body, bound, fold);
+    bound.truncate(bound_len);
+}
+
+// `bound` are the variables that are in the scope of the expression. They shadow the constants.
+fn fold_expr(expr: &mut Expr, bound: &mut Vec<String>, fold: &mut Fold) {
+    match &mut expr.kind {
+        ExprKind::Var(name) => {
+            if !bound.contains(&name.text) {
+                if let Some(value) = fold.value_of(&name.text) {
+                    *expr = value;
+                }
+            }
+            return
+        }
+        ExprKind::Case{subject, clauses} => {
+            fold_expr(subject, bound, fold);
+            for clause in clauses.iter_mut() {
+                fold_clause(clause, bound, fold);
+            }
+        }
+        ExprKind::Receive{clauses, after} => {
+            for clause in clauses.iter_mut() {
+                fold_clause(clause, bound, fold);
+            }
+            if let Some(After{timeout, body}) = after {
+                fold_expr(timeout, bound, fold);
+                fold_expr(body, bound, fold);
+            }
+        }
+        ExprKind::Try{body, clauses, catches} => {
+            fold_expr(body, bound, fold);
+            for clause in clauses.iter_mut() {
+                fold_clause(clause, bound, fold);
+            }
+            for catch in catches.iter_mut() {
+                let bound_len = bound.len();
+                catch.class.vars(bound);
+                fold_clause(&mut catch.clause, bound, fold);
+                bound.truncate(bound_len);
+            }
+        }
+        _ => {
+            for child in expr.children_mut() {
+                fold_expr(child, bound, fold);
+            }
+        }
+    }
+
+    let folded = match &expr.kind {
+        ExprKind::Binop(Binop{kind, lhs, rhs}) => fold_binop(kind, &lhs.kind, &rhs.kind),
+        ExprKind::Not(operand) => match operand.kind {
+            ExprKind::Bool(x) => Some(ExprKind::Bool(!x)),
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(kind) = folded {
+        expr.kind = kind;
+    }
+}
+
+pub fn fold_module(module: &mut Module) -> Option<()> {
+    let mut fold = Fold {
+        pending: std::mem::take(&mut module.consts),
+        folded: BTreeMap::new(),
+        ok: true,
+    };
+    let names: Vec<String> = fold.pending.keys().cloned().collect();
+    for name in names.iter() {
+        fold.value_of(name);
+    }
+    for func in module.funcs.values_mut() {
+        for FuncClause{params, guard, body} in func.clauses.iter_mut() {
+            let mut bound: Vec<String> = params.names().cloned().collect();
+            for param in params.iter() {
+                if let Some(pattern) = &param.pattern {
+                    pattern.vars(&mut bound);
+                }
+            }
+            if let Some(guard) = guard {
+                fold_expr(guard, &mut bound, &mut fold);
+            }
+            fold_expr(body, &mut bound, &mut fold);
+        }
+    }
+    module.consts = fold.folded;
+    if fold.ok { Some(()) } else { None }
+}
@@ -0,0 +1,307 @@
+use builtins;
+use callgraph::CallGraph;
+use compiler::Options;
+use lex::{Token, TokenKind};
+use parser::{Expr, ExprKind, Module, Func, FuncClause, MatchClause, Pattern, PatternKind, After, Type};
+use std::collections::HashMap;
+
+// The biggest body, in the number of the nodes of its expression, that is copied into the callers
+const MAX_INLINE_SIZE: usize = 10;
+
+// The function that is small enough to be copied into its local callers
+struct Inlinable {
+    // In the order of the arguments
+    params: Vec<String>,
+    types: Vec<Type>,
+    body: Expr,
+    // The functions of the module that the body refers to. They must not be
+    // shadowed by the variables of the caller at the call site.
+    callees: Vec<String>,
+}
+
+struct Inline {
+    funcs: HashMap<String, Inlinable>,
+    // For naming the variables that hold the arguments
+    count: usize,
+    type_guards: bool,
+}
+
+fn size(expr: &Expr) -> usize {
+    1 + expr.children().into_iter().map(size).sum::<usize>()
+}
+
+// The bodies that bind variables are not copied, so they can't clash with the variables of the callers
+fn binds_vars(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Case{..} | ExprKind::Receive{..} | ExprKind::Try{..} | ExprKind::Comprehension{..} => true,
+        _ => expr.children().into_iter().any(binds_vars),
+    }
+}
+
+fn is_called(name: &str, expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Call{name: callee, ..} if callee.text == name => true,
+        _ => expr.children().into_iter().any(|child| is_called(name, child)),
+    }
+}
+
+// The copy of the body does not check the type of the parameter at run time,
+// so the argument must be known to pass the check without running it
+fn passes_type_guard(arg: &Expr, typ: &Type) -> bool {
+    matches!((&arg.kind, typ),
+        (_, Type::Any | Type::Var(_)) |
+        (ExprKind::Number(_), Type::Int) |
+        (ExprKind::Float(_), Type::Float) |
+        (ExprKind::Bool(_), Type::Bool | Type::Atom) |
+        (ExprKind::Atom(_), Type::Atom))
+}
+
+fn is_recursive(name: &str, graph: &CallGraph) -> bool {
+    let mut visited: Vec<&String> = Vec::new();
+    let mut pending: Vec<&String> = graph.calls[name].iter().collect();
+    while let Some(callee) = pending.pop() {
+        if callee == name {
+            return true
+        }
+        if !visited.contains(&callee) {
+            visited.push(callee);
+            pending.extend(graph.calls[callee].iter());
+        }
+    }
+    false
+}
+
+fn inlinable(name: &str, func: &Func, graph: &CallGraph) -> Option<Inlinable> {
+    let (params, body) = match &func.clauses[..] {
+        [FuncClause{params, guard: None, body}] => (params, body),
+        _ => return None,
+    };
+    if params.iter().any(|param| param.pattern.is_some()) || size(body) > MAX_INLINE_SIZE || binds_vars(body) || is_recursive(name, graph) {
+        return None
+    }
+    Some(Inlinable {
+        params: params.names().cloned().collect(),
+        types: params.iter().map(|param| param.typ.clone()).collect(),
+        body: body.clone(),
+        callees: graph.calls[name].clone(),
+    })
+}
+
+// Replaces the parameters with the arguments, including the ones holding the funs that are called
+fn substitute(expr: &mut Expr, args: &HashMap<String, Expr>) {
+    match &mut expr.kind {
+        ExprKind::Var(name) => {
+            if let Some(arg) = args.get(&name.text) {
+                *expr = arg.clone();
+            }
+            return
+        }
+        ExprKind::Call{name, ..} => {
+            if let Some(Expr{kind: ExprKind::Var(fun), ..}) = args.get(&name.text) {
+                *name = fun.clone();
+            }
+        }
+        _ => {}
+    }
+    for child in expr.children_mut() {
+        substitute(child, args);
+    }
+}
+
+impl Inline {
+    // The copy of the body of the function in place of its call. The arguments are
+    // evaluated once and in order, just like for the call, by binding them with
+    // the nested `case`s. The literals and the variables are put in place directly.
+    fn expand(&mut self, callee: &str, args: Vec<Expr>, bound: &[String]) -> Expr {
+        let inlinable = &self.funcs[callee];
+        let mut substitutions = HashMap::new();
+        let mut bindings = Vec::new();
+        for (param, arg) in inlinable.params.iter().zip(args) {
+            let direct = match &arg.kind {
+                ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Atom(_) => true,
+                // The funs are called through the hidden variables, so the name of
+                // the call can't be taken by a constructor or a built-in function
+                ExprKind::Var(name) => bound.contains(&name.text) && !is_called(param, &inlinable.body),
+                _ => false,
+            };
+            if direct {
+                substitutions.insert(param.clone(), arg);
+            } else {
+                let token = Token {
+                    kind: TokenKind::Ident,
+                    text: format!("#inline{count}_{param}", count = self.count),
+                    loc: arg.loc.clone(),
+                    end: arg.end.clone(),
+                };
+                self.count += 1;
+                substitutions.insert(param.clone(), Expr {loc: arg.loc.clone(), end: arg.end.clone(), kind: ExprKind::Var(token.clone())});
+                bindings.push((token, arg));
+            }
+        }
+
+        let mut body = inlinable.body.clone();
+        substitute(&mut body, &substitutions);
+        for (token, arg) in bindings.into_iter().rev() {
+            let (loc, end) = (arg.loc.clone(), arg.end.clone());
+            let pattern = Pattern {loc: loc.clone(), end: end.clone(), kind: PatternKind::Var(token)};
+            body = Expr {
+                loc,
+                end,
+                kind: ExprKind::Case {
+                    subject: Box::new(arg),
+                    clauses: vec![MatchClause {pattern, guard: None, body}],
+                },
+            };
+        }
+        body
+    }
+}
+
+fn inline_clause(MatchClause{pattern, body, ..}: &mut MatchClause, bound: &mut Vec<String>, module: &Module, inline: &mut Inline) {
+    let bound_len = bound.len();
+    pattern.vars(bound);
+    inline_expr(body, bound, module, inline);
+    bound.truncate(bound_len);
+}
+
+// `bound` are the variables that are in the scope of the expression. The guards are left alone.
+fn inline_expr(expr: &mut Expr, bound: &mut Vec<String>, module: &Module, inline: &mut Inline) {
+    match &mut expr.kind {
+        ExprKind::Case{subject, clauses} => {
+            inline_expr(subject, bound, module, inline);
+            for clause in clauses.iter_mut() {
+                inline_clause(clause, bound, module, inline);
+            }
+        }
+        ExprKind::Receive{clauses, after} => {
+            for clause in clauses.iter_mut() {
+                inline_clause(clause, bound, module, inline);
+            }
+            if let Some(After{timeout, body}) = after {
+                inline_expr(timeout, bound, module, inline);
+                inline_expr(body, bound, module, inline);
+            }
+        }
+        ExprKind::Try{body, clauses, catches} => {
+            inline_expr(body, bound, module, inline);
+            for clause in clauses.iter_mut() {
+                inline_clause(clause, bound, module, inline);
+            }
+            for catch in catches.iter_mut() {
+                let bound_len = bound.len();
+                catch.class.vars(bound);
+                inline_clause(&mut catch.clause, bound, module, inline);
+                bound.truncate(bound_len);
+            }
+        }
+        _ => {
+            for child in expr.children_mut() {
+                inline_expr(child, bound, module, inline);
+            }
+        }
+    }
+
+    // The call is resolved the same way the compiler does it
+    if let ExprKind::Call{name, args} = &mut expr.kind {
+        let is_inlined = match inline.funcs.get(&name.text) {
+            Some(inlinable) => {
+                inlinable.params.len() == args.len()
+                    && module.find_constructor(&name.text).is_none()
+                    && builtins::find(&name.text, args.len()).is_none()
+                    && !bound.contains(&name.text)
+                    && !inlinable.callees.iter().any(|callee| bound.contains(callee))
+                    && (!inline.type_guards || args.iter().zip(inlinable.types.iter()).all(|(arg, typ)| passes_type_guard(arg, typ)))
+            }
+            None => false,
+        };
+        if is_inlined {
+            let callee = name.text.clone();
+            let args = std::mem::take(args);
+            *expr = inline.expand(&callee, args, bound);
+        }
+    }
+}
+
+// Copies the bodies of the small functions into their local callers. The
+// functions keep their own bodies, so the exported ones can still be called from
+// the outside and replaced by loading the new version of the module. The private
+// ones that are not called anymore are removed. The copies don't check the
+// types of the parameters at run time, so with the type guards on only the
+// calls with the arguments that surely pass them are inlined.
+pub fn inline_module(module: &mut Module, graph: &CallGraph, options: &Options) {
+    // --no-opt turns the inlining off together with the peephole optimiser
+    if !options.optimize {
+        return
+    }
+    let mut inline = Inline {
+        funcs: HashMap::new(),
+        count: 0,
+        type_guards: options.type_guards,
+    };
+    for (name, func) in module.funcs.iter() {
+        if let Some(inlinable) = inlinable(name, func, graph) {
+            inline.funcs.insert(name.clone(), inlinable);
+        }
+    }
+    if inline.funcs.is_empty() {
+        return
+    }
+
+    let mut funcs = std::mem::take(&mut module.funcs);
+    for func in funcs.values_mut() {
+        for FuncClause{params, body, ..} in func.clauses.iter_mut() {
+            let mut bound: Vec<String> = params.names().cloned().collect();
+            for param in params.iter() {
+                if let Some(pattern) = &param.pattern {
+                    pattern.vars(&mut bound);
+                }
+            }
+            inline_expr(body, &mut bound, module, &mut inline);
+        }
+    }
+    module.funcs = funcs;
+
+    let reachable = CallGraph::build(module).reachable(module);
+    module.funcs.retain(|name, _| reachable.contains(name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lex::Lexer;
+
+    // The bodies of the functions after inlining are calls of inc or not
+    fn calls_inc(source: &str, options: &Options) -> Vec<(String, bool)> {
+        let content: Vec<char> = source.chars().collect();
+        let mut lexer = Lexer::new(&content, "test.boom".to_string());
+        let mut module = Module::parse(&mut lexer).expect("the module parses");
+        let graph = CallGraph::build(&module);
+        inline_module(&mut module, &graph, options);
+        module.funcs.iter()
+            .filter(|(name, _)| *name != "inc")
+            .map(|(name, func)| (name.clone(), matches!(&func.clauses[0].body.kind, ExprKind::Call{name, ..} if name.text == "inc")))
+            .collect()
+    }
+
+    const SOURCE: &str = "\
+inc(x int) = x + 1;
+any(v any) = inc(v);
+literal() = inc(1);
+atom() = inc('one');
+";
+
+    #[test]
+    fn type_guards_kept() {
+        assert_eq!(calls_inc(SOURCE, &Options {type_guards: true, optimize: true}), [("any".to_string(), true), ("atom".to_string(), true), ("literal".to_string(), false)]);
+    }
+
+    #[test]
+    fn no_type_guards() {
+        assert_eq!(calls_inc(SOURCE, &Options {type_guards: false, optimize: true}), [("any".to_string(), false), ("atom".to_string(), false), ("literal".to_string(), false)]);
+    }
+
+    #[test]
+    fn no_opt() {
+        assert_eq!(calls_inc(SOURCE, &Options {type_guards: false, optimize: false}), [("any".to_string(), true), ("atom".to_string(), true), ("literal".to_string(), true)]);
+    }
+}
@@ -0,0 +1,397 @@
+// The instructions of the BEAM virtual machine produced by the code generator.
+// They are encoded into the bytes of the Code chunk as the very last step.
+
+#[repr(u8)]
+enum Tag {
+    U = 0,                      // unsigned?
+    I = 1,                      // integer
+    A = 2,                      // atom
+    X = 3,                      // x register
+    Y = 4,                      // y register
+    F = 5,                      // label
+    // H = 6,                   // character?
+    Z = 7,                      // extended
+}
+
+// Extended tags are encoded as the value of Tag::Z
+#[repr(u8)]
+enum ExtTag {
+    List = 1,
+    Literal = 4,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum OpCode {
+    Label = 1,
+    FuncInfo = 2,
+    IntCodeEnd = 3,
+    Call = 4,
+    CallExt = 7,
+    Bif0 = 9,
+    Bif1 = 10,
+    Bif2 = 11,
+    AllocateZero = 14,
+    TestHeap = 16,
+    Deallocate = 18,
+    Return = 19,
+    Send = 20,
+    RemoveMessage = 21,
+    Timeout = 22,
+    LoopRec = 23,
+    LoopRecEnd = 24,
+    Wait = 25,
+    WaitTimeout = 26,
+    IsLt = 39,
+    IsGe = 40,
+    IsEq = 41,
+    IsNe = 42,
+    IsEqExact = 43,
+    IsInteger = 45,
+    IsFloat = 46,
+    IsAtom = 48,
+    IsPid = 49,
+    IsNil = 52,
+    IsList = 55,
+    IsNonemptyList = 56,
+    IsTuple = 57,
+    TestArity = 58,
+    Jump = 61,
+    Move = 64,
+    GetList = 65,
+    GetTupleElement = 66,
+    PutList = 69,
+    CaseEnd = 74,
+    CallFun = 75,
+    IsFunction = 77,
+    Try = 104,
+    TryEnd = 105,
+    TryCase = 106,
+    TryCaseEnd = 107,
+    Raise = 108,
+    IsBoolean = 114,
+    IsFunction2 = 115,
+    GcBif1 = 124,
+    GcBif2 = 125,
+    IsBitstr = 129,
+    IsTaggedTuple = 159,
+    PutMapAssoc = 154,
+    PutMapExact = 155,
+    IsMap = 156,
+    GetMapElements = 158,
+    PutTuple2 = 164,
+    BsStartMatch4 = 170,
+    MakeFun3 = 171,
+    BsCreateBin = 177,
+    BadRecord = 180,
+    BsMatch = 182,
+}
+
+impl OpCode {
+    // Update it when adding an opcode that is bigger than this one
+    pub const MAX: Self = Self::BsMatch;
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Operand {
+    // The plain numbers, like the arities, the sizes and the numbers of the live registers
+    Unsigned(usize),
+    Int(i128),
+    Atom(u32),
+    // The atom with index 0 is the empty list
+    Nil,
+    X(usize),
+    Y(usize),
+    // Label 0 means there is no label. The failures raise exceptions then.
+    Label(u32),
+    Import(u32),
+    Literal(u32),
+    List(Vec<Operand>),
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Instr {
+    Label(u32),
+    FuncInfo{module: u32, name: u32, arity: usize},
+    IntCodeEnd,
+    Call{arity: usize, label: u32},
+    CallExt{arity: usize, import: u32},
+    // Takes the fun from the x register right after the arguments
+    CallFun{arity: usize},
+    Bif0{bif: u32, dst: Operand},
+    Bif1{fail: u32, bif: u32, arg: Operand, dst: Operand},
+    Bif2{fail: u32, bif: u32, lhs: Operand, rhs: Operand, dst: Operand},
+    GcBif1{fail: u32, live: usize, bif: u32, arg: Operand, dst: Operand},
+    GcBif2{fail: u32, live: usize, bif: u32, lhs: Operand, rhs: Operand, dst: Operand},
+    AllocateZero{stack_need: usize, live: usize},
+    Deallocate(usize),
+    TestHeap{alloc: usize, live: usize},
+    Return,
+    Send,
+    RemoveMessage,
+    Timeout,
+    LoopRec{fail: u32, dst: Operand},
+    LoopRecEnd(u32),
+    Wait(u32),
+    WaitTimeout{label: u32, timeout: Operand},
+    // The type checks and the comparisons jump to `fail` unless they hold
+    Test{op: OpCode, fail: u32, args: Vec<Operand>},
+    Jump(u32),
+    Move{src: Operand, dst: Operand},
+    GetList{src: Operand, head: Operand, tail: Operand},
+    GetTupleElement{src: Operand, index: usize, dst: Operand},
+    PutList{head: Operand, tail: Operand, dst: Operand},
+    PutTuple2{dst: Operand, elements: Vec<Operand>},
+    CaseEnd(Operand),
+    Try{tag: Operand, handler: u32},
+    TryEnd(Operand),
+    TryCase(Operand),
+    TryCaseEnd(Operand),
+    Raise{stacktrace: Operand, reason: Operand},
+    PutMapAssoc{src: Operand, dst: Operand, live: usize, pairs: Vec<Operand>},
+    PutMapExact{src: Operand, dst: Operand, live: usize, pairs: Vec<Operand>},
+    GetMapElements{fail: u32, src: Operand, pairs: Vec<Operand>},
+    BsStartMatch4{fail: u32, live: usize, src: Operand, dst: Operand},
+    BsMatch{fail: u32, context: Operand, commands: Vec<Operand>},
+    BsCreateBin{live: usize, dst: Operand, segments: Vec<Operand>},
+    MakeFun3{lambda: u32, dst: Operand, free: Vec<Operand>},
+    BadRecord(Operand),
+}
+
+impl Instr {
+    // The labels the instruction may jump to. The entries of the functions
+    // taken by `call` are not included, since the control comes back from them.
+    pub fn targets_mut(&mut self) -> Vec<&mut u32> {
+        match self {
+            Instr::Bif1{fail, ..} | Instr::Bif2{fail, ..} | Instr::GcBif1{fail, ..} | Instr::GcBif2{fail, ..} |
+            Instr::LoopRec{fail, ..} | Instr::Test{fail, ..} | Instr::GetMapElements{fail, ..} |
+            Instr::BsStartMatch4{fail, ..} | Instr::BsMatch{fail, ..} => vec![fail],
+            Instr::LoopRecEnd(label) | Instr::Wait(label) | Instr::WaitTimeout{label, ..} | Instr::Jump(label) => vec![label],
+            Instr::Try{handler, ..} => vec![handler],
+            _ => vec![],
+        }
+    }
+
+    // The control never goes to the next instruction
+    pub fn is_terminal(&self) -> bool {
+        matches!(self,
+            Instr::Return | Instr::Jump(_) | Instr::LoopRecEnd(_) | Instr::Wait(_) | Instr::CaseEnd(_) |
+            Instr::TryCaseEnd(_) | Instr::Raise{..} | Instr::BadRecord(_) | Instr::FuncInfo{..})
+    }
+
+    // The only register the instruction writes, if it writes just one
+    pub fn dst_mut(&mut self) -> Option<&mut Operand> {
+        match self {
+            Instr::Move{dst, ..} | Instr::Bif0{dst, ..} | Instr::Bif1{dst, ..} | Instr::Bif2{dst, ..} |
+            Instr::GcBif1{dst, ..} | Instr::GcBif2{dst, ..} | Instr::GetTupleElement{dst, ..} | Instr::PutList{dst, ..} |
+            Instr::PutTuple2{dst, ..} | Instr::PutMapAssoc{dst, ..} | Instr::PutMapExact{dst, ..} |
+            Instr::BsCreateBin{dst, ..} | Instr::MakeFun3{dst, ..} => Some(dst),
+            _ => None,
+        }
+    }
+
+    // The opcode and the operands in the order the loader expects them
+    fn parts(&self) -> (OpCode, Vec<Operand>) {
+        use self::Operand::{Unsigned, Atom, Label, Import, List};
+        match self {
+            Instr::Label(label) => (OpCode::Label, vec![Unsigned(*label as usize)]),
+            Instr::FuncInfo{module, name, arity} => (OpCode::FuncInfo, vec![Atom(*module), Atom(*name), Unsigned(*arity)]),
+            Instr::IntCodeEnd => (OpCode::IntCodeEnd, vec![]),
+            Instr::Call{arity, label} => (OpCode::Call, vec![Unsigned(*arity), Label(*label)]),
+            Instr::CallExt{arity, import} => (OpCode::CallExt, vec![Unsigned(*arity), Import(*import)]),
+            Instr::CallFun{arity} => (OpCode::CallFun, vec![Unsigned(*arity)]),
+            Instr::Bif0{bif, dst} => (OpCode::Bif0, vec![Import(*bif), dst.clone()]),
+            Instr::Bif1{fail, bif, arg, dst} => (OpCode::Bif1, vec![Label(*fail), Import(*bif), arg.clone(), dst.clone()]),
+            Instr::Bif2{fail, bif, lhs, rhs, dst} => {
+                (OpCode::Bif2, vec![Label(*fail), Import(*bif), lhs.clone(), rhs.clone(), dst.clone()])
+            }
+            Instr::GcBif1{fail, live, bif, arg, dst} => {
+                (OpCode::GcBif1, vec![Label(*fail), Unsigned(*live), Import(*bif), arg.clone(), dst.clone()])
+            }
+            Instr::GcBif2{fail, live, bif, lhs, rhs, dst} => {
+                (OpCode::GcBif2, vec![Label(*fail), Unsigned(*live), Import(*bif), lhs.clone(), rhs.clone(), dst.clone()])
+            }
+            Instr::AllocateZero{stack_need, live} => (OpCode::AllocateZero, vec![Unsigned(*stack_need), Unsigned(*live)]),
+            Instr::Deallocate(size) => (OpCode::Deallocate, vec![Unsigned(*size)]),
+            Instr::TestHeap{alloc, live} => (OpCode::TestHeap, vec![Unsigned(*alloc), Unsigned(*live)]),
+            Instr::Return => (OpCode::Return, vec![]),
+            Instr::Send => (OpCode::Send, vec![]),
+            Instr::RemoveMessage => (OpCode::RemoveMessage, vec![]),
+            Instr::Timeout => (OpCode::Timeout, vec![]),
+            Instr::LoopRec{fail, dst} => (OpCode::LoopRec, vec![Label(*fail), dst.clone()]),
+            Instr::LoopRecEnd(label) => (OpCode::LoopRecEnd, vec![Label(*label)]),
+            Instr::Wait(label) => (OpCode::Wait, vec![Label(*label)]),
+            Instr::WaitTimeout{label, timeout} => (OpCode::WaitTimeout, vec![Label(*label), timeout.clone()]),
+            Instr::Test{op, fail, args} => {
+                let mut operands = vec![Label(*fail)];
+                operands.extend(args.iter().cloned());
+                (*op, operands)
+            }
+            Instr::Jump(label) => (OpCode::Jump, vec![Label(*label)]),
+            Instr::Move{src, dst} => (OpCode::Move, vec![src.clone(), dst.clone()]),
+            Instr::GetList{src, head, tail} => (OpCode::GetList, vec![src.clone(), head.clone(), tail.clone()]),
+            Instr::GetTupleElement{src, index, dst} => (OpCode::GetTupleElement, vec![src.clone(), Unsigned(*index), dst.clone()]),
+            Instr::PutList{head, tail, dst} => (OpCode::PutList, vec![head.clone(), tail.clone(), dst.clone()]),
+            Instr::PutTuple2{dst, elements} => (OpCode::PutTuple2, vec![dst.clone(), List(elements.clone())]),
+            Instr::CaseEnd(value) => (OpCode::CaseEnd, vec![value.clone()]),
+            Instr::Try{tag, handler} => (OpCode::Try, vec![tag.clone(), Label(*handler)]),
+            Instr::TryEnd(tag) => (OpCode::TryEnd, vec![tag.clone()]),
+            Instr::TryCase(tag) => (OpCode::TryCase, vec![tag.clone()]),
+            Instr::TryCaseEnd(value) => (OpCode::TryCaseEnd, vec![value.clone()]),
+            Instr::Raise{stacktrace, reason} => (OpCode::Raise, vec![stacktrace.clone(), reason.clone()]),
+            Instr::PutMapAssoc{src, dst, live, pairs} => {
+                (OpCode::PutMapAssoc, vec![Label(0), src.clone(), dst.clone(), Unsigned(*live), List(pairs.clone())])
+            }
+            Instr::PutMapExact{src, dst, live, pairs} => {
+                (OpCode::PutMapExact, vec![Label(0), src.clone(), dst.clone(), Unsigned(*live), List(pairs.clone())])
+            }
+            Instr::GetMapElements{fail, src, pairs} => (OpCode::GetMapElements, vec![Label(*fail), src.clone(), List(pairs.clone())]),
+            Instr::BsStartMatch4{fail, live, src, dst} => {
+                (OpCode::BsStartMatch4, vec![Label(*fail), Unsigned(*live), src.clone(), dst.clone()])
+            }
+            Instr::BsMatch{fail, context, commands} => (OpCode::BsMatch, vec![Label(*fail), context.clone(), List(commands.clone())]),
+            // The binaries are built without the fail label, the extra heap words and with the unit of 1
+            Instr::BsCreateBin{live, dst, segments} => {
+                (OpCode::BsCreateBin, vec![Label(0), Unsigned(0), Unsigned(*live), Unsigned(1), dst.clone(), List(segments.clone())])
+            }
+            Instr::MakeFun3{lambda, dst, free} => (OpCode::MakeFun3, vec![Unsigned(*lambda as usize), dst.clone(), List(free.clone())]),
+            Instr::BadRecord(value) => (OpCode::BadRecord, vec![value.clone()]),
+        }
+    }
+}
+
+pub fn encode_code(code: &[Instr]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for instr in code.iter() {
+        let (opcode, operands) = instr.parts();
+        bytes.push(opcode as u8);
+        for operand in operands.iter() {
+            encode_operand(operand, &mut bytes);
+        }
+    }
+    bytes
+}
+
+fn encode_operand(operand: &Operand, bytes: &mut Vec<u8>) {
+    match operand {
+        Operand::Unsigned(n) => bytes.extend(encode_arg(Tag::U, *n as i128)),
+        Operand::Int(x) => bytes.extend(encode_arg(Tag::I, *x)),
+        Operand::Atom(id) => bytes.extend(encode_arg(Tag::A, *id as i128)),
+        Operand::Nil => bytes.extend(encode_arg(Tag::A, 0)),
+        Operand::X(n) => bytes.extend(encode_arg(Tag::X, *n as i128)),
+        Operand::Y(n) => bytes.extend(encode_arg(Tag::Y, *n as i128)),
+        Operand::Label(label) => bytes.extend(encode_arg(Tag::F, *label as i128)),
+        Operand::Import(index) => bytes.extend(encode_arg(Tag::U, *index as i128)),
+        Operand::Literal(index) => {
+            bytes.extend(encode_arg(Tag::Z, ExtTag::Literal as i128));
+            bytes.extend(encode_arg(Tag::U, *index as i128));
+        }
+        Operand::List(items) => {
+            bytes.extend(encode_arg(Tag::Z, ExtTag::List as i128));
+            bytes.extend(encode_arg(Tag::U, items.len() as i128));
+            for item in items.iter() {
+                encode_operand(item, bytes);
+            }
+        }
+    }
+}
+
+// The integers of any size fit the compact encoding, so the folded constants
+// that overflow the machine words are loaded as bignums
+fn encode_arg(tag: Tag, n: i128) -> Vec<u8> {
+    if (0..16).contains(&n) {
+        // (N bsl 4) bor Tag;
+        let tag = tag as u8;
+        let n = n as u8;
+        vec![(n<<4)|tag]
+    } else if (0..0x800).contains(&n) {
+        // [((N bsr 3) band 2#11100000) bor Tag bor 2#00001000, N band 16#ff];
+        let tag = tag as u32;
+        let n = n as u32;
+        let a = (((n>>3)&0b11100000u32)|tag|0b00001000u32) as u8;
+        let b = (n&0xFF) as u8;
+        vec![a, b]
+    } else {
+        // The shortest two's complement big-endian bytes, but at least 2 of them
+        let bytes = n.to_be_bytes();
+        let mut start = 0;
+        while start + 2 < bytes.len() {
+            let redundant = (bytes[start] == 0x00 && bytes[start + 1] < 0x80)
+                         || (bytes[start] == 0xFF && bytes[start + 1] >= 0x80);
+            if !redundant {
+                break
+            }
+            start += 1;
+        }
+        let bytes = &bytes[start..];
+        let mut result = if bytes.len() <= 8 {
+            // [(Num-2) bsl 5 bor 2#11000 bor Tag | Bytes]
+            vec![(((bytes.len() - 2) as u8)<<5)|0b00011000|(tag as u8)]
+        } else {
+            // [2#11111000 bor Tag, encode(?tag_u, Num-9) | Bytes]
+            let mut result = vec![0b11111000|(tag as u8)];
+            result.extend(encode_arg(Tag::U, (bytes.len() - 9) as i128));
+            result
+        };
+        result.extend(bytes);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(operand: Operand) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        encode_operand(&operand, &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn small_integers() {
+        assert_eq!(encode_arg(Tag::U, 0), [0x00]);
+        assert_eq!(encode_arg(Tag::I, 15), [0xF1]);
+        assert_eq!(encode_arg(Tag::I, 16), [0x09, 0x10]);
+        assert_eq!(encode_arg(Tag::U, 0x7FF), [0xE8, 0xFF]);
+    }
+
+    #[test]
+    fn large_integers() {
+        assert_eq!(encode_arg(Tag::I, 0x800), [0x19, 0x08, 0x00]);
+        assert_eq!(encode_arg(Tag::I, 0xFFFF), [0x39, 0x00, 0xFF, 0xFF]);
+        assert_eq!(encode_arg(Tag::I, i64::MAX as i128), [0xD9, 0x7F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn negative_integers() {
+        assert_eq!(encode_arg(Tag::I, -1), [0x19, 0xFF, 0xFF]);
+        assert_eq!(encode_arg(Tag::I, -200), [0x19, 0xFF, 0x38]);
+        assert_eq!(encode_arg(Tag::I, -0x8000), [0x19, 0x80, 0x00]);
+        assert_eq!(encode_arg(Tag::I, -0x8001), [0x39, 0xFF, 0x7F, 0xFF]);
+    }
+
+    #[test]
+    fn bignums() {
+        // 2^70 takes 9 bytes, so their number goes after the tag
+        assert_eq!(encode_arg(Tag::I, 1 << 70), [0xF9, 0x00, 0x40, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode_arg(Tag::I, -(1 << 70)), [0xF9, 0x00, 0xC0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let mut expected = vec![0xF9, 0x70];
+        expected.extend(i128::MAX.to_be_bytes());
+        assert_eq!(encode_arg(Tag::I, i128::MAX), expected);
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(encode(Operand::Atom(3)), [0x32]);
+        assert_eq!(encode(Operand::Nil), [0x02]);
+        assert_eq!(encode(Operand::Atom(300)), [0x2A, 0x2C]);
+        assert_eq!(encode(Operand::Label(1)), [0x15]);
+        assert_eq!(encode(Operand::Label(0x100)), [0x2D, 0x00]);
+        assert_eq!(encode(Operand::X(2)), [0x23]);
+        assert_eq!(encode(Operand::Y(1)), [0x14]);
+        assert_eq!(encode(Operand::X(1023)), [0x6B, 0xFF]);
+        assert_eq!(encode(Operand::Literal(2)), [0x47, 0x20]);
+        assert_eq!(encode(Operand::List(vec![Operand::X(0), Operand::Int(-1)])), [0x17, 0x20, 0x03, 0x19, 0xFF, 0xFF]);
+    }
+}
@@ -1,30 +1,119 @@
-use diag::Loc;
+use diag::{Loc, Span, ToSpan};
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum TokenKind {
     Ident,
     Number,
+    Atom,
+    TypeVar,
+
+    And,
+    Or,
+    Not,
+    True,
+    False,
+    When,
+    Case,
+    Of,
+    Receive,
+    After,
+    Try,
+    Catch,
+    Record,
+    Type,
+    Const,
+    Private,
+    End,
 
     Equals,
+    EqualsEquals,
+    NotEquals,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
     Plus,
     Minus,
+    Star,
+    Slash,
     SemiColon,
     OpenParen,
     ClosedParen,
     Colon,
+    Comma,
+    OpenCurly,
+    ClosedCurly,
+    OpenBracket,
+    ClosedBracket,
+    Arrow,
+    Bang,
+    Dot,
+    Bar,
+    DoubleBar,
+    LeftArrow,
+    Hash,
+    DoubleLess,
+    DoubleGreater,
+    FatArrow,
+    ColonEquals,
 
-    End,
+    EndOfInput,
     Unknown
 }
 
+// Longer tokens must go before their prefixes
 const FIXED_TOKENS: &[(&[char], TokenKind)] = &[
+    (&['=', '='], TokenKind::EqualsEquals),
+    (&['!', '='], TokenKind::NotEquals),
+    (&['-', '>'], TokenKind::Arrow),
+    (&['=', '>'], TokenKind::FatArrow),
+    (&[':', '='], TokenKind::ColonEquals),
+    (&['<', '<'], TokenKind::DoubleLess),
+    (&['<', '-'], TokenKind::LeftArrow),
+    (&['|', '|'], TokenKind::DoubleBar),
+    (&['>', '>'], TokenKind::DoubleGreater),
+    (&['<', '='], TokenKind::LessEquals),
+    (&['>', '='], TokenKind::GreaterEquals),
     (&['='], TokenKind::Equals),
+    (&['<'], TokenKind::Less),
+    (&['>'], TokenKind::Greater),
     (&['+'], TokenKind::Plus),
     (&['-'], TokenKind::Minus),
+    (&['*'], TokenKind::Star),
+    (&['/'], TokenKind::Slash),
     (&[';'], TokenKind::SemiColon),
     (&[':'], TokenKind::Colon),
     (&['('], TokenKind::OpenParen),
     (&[')'], TokenKind::ClosedParen),
+    (&[','], TokenKind::Comma),
+    (&['{'], TokenKind::OpenCurly),
+    (&['}'], TokenKind::ClosedCurly),
+    (&['['], TokenKind::OpenBracket),
+    (&[']'], TokenKind::ClosedBracket),
+    (&['!'], TokenKind::Bang),
+    (&['.'], TokenKind::Dot),
+    (&['|'], TokenKind::Bar),
+    (&['#'], TokenKind::Hash),
+];
+
+const KEYWORDS: &[(&str, TokenKind)] = &[
+    ("and", TokenKind::And),
+    ("or", TokenKind::Or),
+    ("not", TokenKind::Not),
+    ("true", TokenKind::True),
+    ("false", TokenKind::False),
+    ("when", TokenKind::When),
+    ("case", TokenKind::Case),
+    ("of", TokenKind::Of),
+    ("receive", TokenKind::Receive),
+    ("after", TokenKind::After),
+    ("try", TokenKind::Try),
+    ("catch", TokenKind::Catch),
+    ("record", TokenKind::Record),
+    ("type", TokenKind::Type),
+    ("const", TokenKind::Const),
+    ("private", TokenKind::Private),
+    ("end", TokenKind::End),
 ];
 
 impl TokenKind {
@@ -32,27 +121,81 @@ impl TokenKind {
         match self {
             Self::Ident => "identifier",
             Self::Number => "number",
+            Self::Atom => "atom",
+            Self::TypeVar => "type variable",
+
+            Self::And => "keyword `and`",
+            Self::Or => "keyword `or`",
+            Self::Not => "keyword `not`",
+            Self::True => "keyword `true`",
+            Self::False => "keyword `false`",
+            Self::When => "keyword `when`",
+            Self::Case => "keyword `case`",
+            Self::Of => "keyword `of`",
+            Self::Receive => "keyword `receive`",
+            Self::After => "keyword `after`",
+            Self::Try => "keyword `try`",
+            Self::Catch => "keyword `catch`",
+            Self::Record => "keyword `record`",
+            Self::Type => "keyword `type`",
+            Self::Const => "keyword `const`",
+            Self::Private => "keyword `private`",
+            Self::End => "keyword `end`",
 
             Self::Equals => "equals",
+            Self::EqualsEquals => "double equals",
+            Self::NotEquals => "not equals",
+            Self::Less => "less than",
+            Self::LessEquals => "less than or equals",
+            Self::Greater => "greater than",
+            Self::GreaterEquals => "greater than or equals",
             Self::Plus => "plus",
             Self::Minus => "minus",
+            Self::Star => "star",
+            Self::Slash => "slash",
             Self::SemiColon => "semi-colon",
             Self::OpenParen => "open paren",
             Self::ClosedParen => "closed paren",
             Self::Colon => "colon",
+            Self::Comma => "comma",
+            Self::OpenCurly => "open curly",
+            Self::ClosedCurly => "closed curly",
+            Self::OpenBracket => "open bracket",
+            Self::ClosedBracket => "closed bracket",
+            Self::Arrow => "arrow",
+            Self::Bang => "bang",
+            Self::Dot => "dot",
+            Self::Bar => "bar",
+            Self::DoubleBar => "double bar",
+            Self::LeftArrow => "left arrow",
+            Self::Hash => "hash",
+            Self::DoubleLess => "double less than",
+            Self::DoubleGreater => "double greater than",
+            Self::FatArrow => "fat arrow",
+            Self::ColonEquals => "colon equals",
 
-            Self::End => "end of input",
+            Self::EndOfInput => "end of input",
             Self::Unknown => "unknown token",
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Token {
     pub kind: TokenKind,
     pub text: String,
     pub loc: Loc,
+    // Right after the last character of the token
+    pub end: Loc,
 }
 
+impl ToSpan for Token {
+    fn to_span(&self) -> Span {
+        Span {start: self.loc.clone(), end: self.end.clone()}
+    }
+}
+
+#[derive(Clone)]
 pub struct Lexer<'a> {
     content: &'a [char],
     file_path: String,
@@ -66,18 +209,38 @@ impl<'a> Lexer<'a> {
         Self {content, file_path, pos: 0, bol: 0, row: 0}
     }
 
-    pub fn expect_tokens(&mut self, expected_kinds: &[TokenKind]) -> Option<Token> {
+    pub fn peek_token(&mut self) -> Token {
+        let (pos, bol, row) = (self.pos, self.bol, self.row);
         let token = self.next_token();
+        (self.pos, self.bol, self.row) = (pos, bol, row);
+        token
+    }
+
+    pub fn expect_tokens(&mut self, expected_kinds: &[TokenKind]) -> Option<Token> {
+        self.expect_kinds(false, expected_kinds)
+    }
+
+    // Like expect_tokens, but 'a is the type variable rather than the start of the atom
+    pub fn expect_type_tokens(&mut self, expected_kinds: &[TokenKind]) -> Option<Token> {
+        self.expect_kinds(true, expected_kinds)
+    }
+
+    // The unexpected token is left in place, so the recovery after the
+    // syntax error starts from it
+    fn expect_kinds(&mut self, type_var: bool, expected_kinds: &[TokenKind]) -> Option<Token> {
+        let (pos, bol, row) = (self.pos, self.bol, self.row);
+        let token = self.lex_token(type_var);
         for kind in expected_kinds {
             if token.kind == *kind {
                 return Some(token)
             }
         }
+        (self.pos, self.bol, self.row) = (pos, bol, row);
 
         let mut expected_list = String::new();
         for (i, kind) in expected_kinds.iter().enumerate() {
             if i == 0 {
-                expected_list.push_str(&format!("{}", kind.human()))
+                expected_list.push_str(kind.human())
             } else if i + 1 >= expected_kinds.len() {
                 expected_list.push_str(&format!(", or {}", kind.human()))
             } else {
@@ -85,7 +248,7 @@ impl<'a> Lexer<'a> {
             }
         }
 
-        report!(token.loc, "ERROR", "Expected {expected_list}, but got {actual}",
+        report!(token, "ERROR", E0001, "Expected {expected_list}, but got {actual}",
                 actual = token.kind.human());
         None
     }
@@ -103,7 +266,28 @@ impl<'a> Lexer<'a> {
         }
     }
 
-    fn next_token(&mut self) -> Token {
+    fn peek_char(&self, offset: usize) -> Option<char> {
+        self.content.get(self.pos + offset).cloned()
+    }
+
+    fn chop_digits(&mut self, text: &mut String) {
+        while let Some(x) = self.current_char() {
+            if x.is_numeric() {
+                self.chop_char();
+                text.push(x);
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn next_token(&mut self) -> Token {
+        self.lex_token(false)
+    }
+
+    // Only the types have the type variables, so only there 'a is one.
+    // Anywhere else it starts the atom, like 'hello world'.
+    fn lex_token(&mut self, type_var: bool) -> Token {
         'trim_whitespaces_and_comments: loop {
             self.trim_whitespaces();
             if self.starts_with(&['/', '/']) {
@@ -113,11 +297,7 @@ impl<'a> Lexer<'a> {
             }
         }
 
-        let loc = Loc {
-            file_path: self.file_path.clone(),
-            row: self.row + 1,
-            col: self.pos - self.bol + 1,
-        };
+        let loc = self.loc();
 
         let x = if let Some(x) = self.current_char() {
             x
@@ -125,50 +305,113 @@ impl<'a> Lexer<'a> {
             return Token {
                 text: "".to_string(),
                 loc,
-                kind: TokenKind::End,
+                end: self.loc(),
+                kind: TokenKind::EndOfInput,
             }
         };
 
-        if x.is_alphabetic() {
+        if x.is_alphabetic() || x == '_' {
             let mut text = String::new();
             while let Some(x) = self.current_char() {
-                if x.is_alphanumeric() {
+                if x.is_alphanumeric() || x == '_' {
                     self.chop_char();
                     text.push(x);
                 } else {
                     break;
                 }
             }
+            let kind = KEYWORDS
+                .iter()
+                .find(|(keyword, _)| *keyword == text)
+                .map(|(_, kind)| *kind)
+                .unwrap_or(TokenKind::Ident);
             return Token {
                 text,
                 loc,
-                kind: TokenKind::Ident,
+                end: self.loc(),
+                kind,
             }
         }
 
         if x.is_numeric() {
             let mut text = String::new();
-            while let Some(x) = self.current_char() {
-                if x.is_numeric() {
+            self.chop_digits(&mut text);
+
+            // Fractional part: 3.14
+            if self.current_char() == Some('.') && self.peek_char(1).map(|x| x.is_numeric()).unwrap_or(false) {
+                self.chop_char();
+                text.push('.');
+                self.chop_digits(&mut text);
+            }
+
+            // Exponent: 1e-3, 2.5E+10
+            if let Some(e @ ('e' | 'E')) = self.current_char() {
+                let sign = self.peek_char(1).filter(|x| *x == '-' || *x == '+');
+                let digit_offset = if sign.is_some() { 2 } else { 1 };
+                if self.peek_char(digit_offset).map(|x| x.is_numeric()).unwrap_or(false) {
                     self.chop_char();
-                    text.push(x);
-                } else {
-                    break;
+                    text.push(e);
+                    if let Some(sign) = sign {
+                        self.chop_char();
+                        text.push(sign);
+                    }
+                    self.chop_digits(&mut text);
                 }
             }
+
             return Token {
                 text,
                 loc,
+                end: self.loc(),
                 kind: TokenKind::Number,
             }
         }
 
+        if x == '\'' {
+            self.chop_char();
+            let name_len = self.content[self.pos..].iter().take_while(|x| x.is_alphanumeric() || **x == '_').count();
+            if type_var && name_len > 0 && self.content[self.pos].is_alphabetic() {
+                let text = self.content[self.pos..self.pos + name_len].iter().collect();
+                self.chop_chars(name_len);
+                return Token {
+                    text,
+                    loc,
+                    end: self.loc(),
+                    kind: TokenKind::TypeVar,
+                }
+            }
+            let mut text = String::new();
+            while let Some(x) = self.current_char() {
+                if x == '\'' || x == '\n' {
+                    break
+                }
+                self.chop_char();
+                text.push(x);
+            }
+            if self.current_char() == Some('\'') {
+                self.chop_char();
+                return Token {
+                    text,
+                    loc,
+                    end: self.loc(),
+                    kind: TokenKind::Atom,
+                }
+            }
+            return Token {
+                text,
+                loc,
+                end: self.loc(),
+                kind: TokenKind::Unknown,
+            }
+        }
+
         for &(prefix, kind) in FIXED_TOKENS.iter() {
             if self.starts_with(prefix) {
                 self.chop_chars(prefix.len());
                 return Token {
                     text: prefix.iter().collect(),
                     loc,
+                    end: self.loc(),
                     kind,
                 }
             }
@@ -178,10 +421,21 @@ impl<'a> Lexer<'a> {
         Token {
             text: x.to_string(),
             loc,
+            end: self.loc(),
             kind: TokenKind::Unknown,
         }
     }
 
+    // Right after the last consumed token, because the whitespaces are only
+    // skipped before the next one
+    pub fn loc(&self) -> Loc {
+        Loc {
+            file_path: self.file_path.clone(),
+            row: self.row + 1,
+            col: self.pos - self.bol + 1,
+        }
+    }
+
     fn trim_whitespaces(&mut self) {
         while self.current_char().map(|x| x.is_whitespace()).unwrap_or(false) {
             self.chop_char();
@@ -208,3 +462,38 @@ impl<'a> Lexer<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str, type_var: bool) -> Token {
+        let content: Vec<char> = source.chars().collect();
+        let mut lexer = Lexer::new(&content, "test.boom".to_string());
+        lexer.lex_token(type_var)
+    }
+
+    #[test]
+    fn quoted_atoms() {
+        for (source, text) in [("'hello world'", "hello world"), ("'foo-bar'", "foo-bar"), ("'a'", "a"), ("'ok' ;", "ok")] {
+            let token = lex(source, false);
+            assert!(token.kind == TokenKind::Atom, "{} is not an atom", source);
+            assert_eq!(token.text, text);
+        }
+    }
+
+    #[test]
+    fn type_vars() {
+        for source in ["'a", "'a)", "'a y 'b", "'a, 'b"] {
+            let token = lex(source, true);
+            assert!(token.kind == TokenKind::TypeVar, "{} is not a type variable", source);
+            assert_eq!(token.text, "a");
+        }
+    }
+
+    #[test]
+    fn unclosed_atom() {
+        let token = lex("'a) = x;\n", false);
+        assert!(token.kind == TokenKind::Unknown);
+    }
+}
@@ -1,70 +1,1262 @@
 use diag::*;
 use lex::{Token, TokenKind, Lexer};
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
+use std::cell::RefCell;
+use std::fmt;
 
+#[derive(Clone)]
 pub enum BinopKind {
     Sum,
     Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Eq,
+    NotEq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
 }
 
 fn binop_of_token(kind: TokenKind) -> Option<BinopKind> {
     match kind {
         TokenKind::Plus => Some(BinopKind::Sum),
         TokenKind::Minus => Some(BinopKind::Sub),
+        TokenKind::Star => Some(BinopKind::Mul),
+        TokenKind::Slash => Some(BinopKind::Div),
+        TokenKind::And => Some(BinopKind::And),
+        TokenKind::Or => Some(BinopKind::Or),
+        TokenKind::EqualsEquals => Some(BinopKind::Eq),
+        TokenKind::NotEquals => Some(BinopKind::NotEq),
+        TokenKind::Less => Some(BinopKind::Less),
+        TokenKind::LessEquals => Some(BinopKind::LessEq),
+        TokenKind::Greater => Some(BinopKind::Greater),
+        TokenKind::GreaterEquals => Some(BinopKind::GreaterEq),
         _ => None,
     }
 }
 
+impl BinopKind {
+    const MAX_PRECEDENCE: usize = 4;
+
+    fn precedence(&self) -> usize {
+        match self {
+            Self::Or => 0,
+            Self::And => 1,
+            Self::Eq | Self::NotEq | Self::Less | Self::LessEq | Self::Greater | Self::GreaterEq => 2,
+            Self::Sum | Self::Sub => 3,
+            Self::Mul | Self::Div => 4,
+        }
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        self.precedence() == 2
+    }
+}
+
+#[derive(Clone)]
 pub struct Binop {
     pub kind: BinopKind,
     pub lhs: Box<Expr>,
     pub rhs: Box<Expr>
 }
 
-pub enum Expr {
+#[derive(Clone, Copy, PartialEq)]
+pub enum SegmentKind {
+    Integer,
+    Float,
+    // Made of whole bytes
+    Binary,
+    Bits,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Endianness {
+    Big,
+    Little,
+    Native,
+}
+
+// The type specifiers of a segment, like `/binary` or `/little-signed-unit:8`
+#[derive(Clone, Copy)]
+pub struct SegmentSpec {
+    pub kind: SegmentKind,
+    pub endianness: Endianness,
+    pub signed: bool,
+    pub unit: Option<usize>,
+}
+
+impl Default for SegmentSpec {
+    fn default() -> Self {
+        SegmentSpec {
+            kind: SegmentKind::Integer,
+            endianness: Endianness::Big,
+            signed: false,
+            unit: None,
+        }
+    }
+}
+
+// The sizes in the commands of bs_match are the small integers of the VM,
+// which have 60 bits on the 64-bit machines
+pub const MAX_SEGMENT_BITS: usize = (1 << 59) - 1;
+
+impl SegmentSpec {
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let mut spec = SegmentSpec::default();
+        loop {
+            let token = lexer.expect_tokens(&[TokenKind::Ident])?;
+            match token.text.as_str() {
+                "integer" => spec.kind = SegmentKind::Integer,
+                "float" => spec.kind = SegmentKind::Float,
+                "binary" | "bytes" => spec.kind = SegmentKind::Binary,
+                "bits" | "bitstring" => spec.kind = SegmentKind::Bits,
+                "big" => spec.endianness = Endianness::Big,
+                "little" => spec.endianness = Endianness::Little,
+                "native" => spec.endianness = Endianness::Native,
+                "signed" => spec.signed = true,
+                "unsigned" => spec.signed = false,
+                "unit" => {
+                    lexer.expect_tokens(&[TokenKind::Colon])?;
+                    let number = lexer.expect_tokens(&[TokenKind::Number])?;
+                    match number.text.parse::<usize>() {
+                        Ok(unit) if (1..=256).contains(&unit) => spec.unit = Some(unit),
+                        _ => {
+                            report!(&number, "ERROR", E0029, "Unit must be a number from 1 to 256");
+                            return None
+                        }
+                    }
+                }
+                unknown => {
+                    report!(&token, "ERROR", E0029, "Unknown type specifier `{unknown}` of the segment");
+                    return None
+                }
+            }
+            if lexer.peek_token().kind != TokenKind::Minus {
+                return Some(spec)
+            }
+            lexer.next_token();
+        }
+    }
+
+    // The size of the segment is measured in units
+    pub fn unit(&self) -> usize {
+        match (self.unit, self.kind) {
+            (Some(unit), _) => unit,
+            (None, SegmentKind::Binary) => 8,
+            (None, _) => 1,
+        }
+    }
+
+    // The size of the segment when it's not specified, None means the whole rest
+    pub fn default_size(&self) -> Option<usize> {
+        match self.kind {
+            SegmentKind::Integer => Some(8),
+            SegmentKind::Float => Some(64),
+            SegmentKind::Binary | SegmentKind::Bits => None,
+        }
+    }
+}
+
+// value[:size][/spec] of a binary. The value and the size are either
+// expressions or patterns.
+#[derive(Clone)]
+pub struct Segment<T> {
+    pub loc: Loc,
+    pub end: Loc,
+    pub value: T,
+    pub size: Option<T>,
+    pub spec: SegmentSpec,
+}
+
+impl<T> Segment<T> {
+    fn parse(lexer: &mut Lexer, parse_operand: fn(&mut Lexer) -> Option<T>) -> Option<Self> {
+        let loc = lexer.peek_token().loc;
+        let value = parse_operand(lexer)?;
+        let size = if lexer.peek_token().kind == TokenKind::Colon {
+            lexer.next_token();
+            Some(parse_operand(lexer)?)
+        } else {
+            None
+        };
+        let spec = if lexer.peek_token().kind == TokenKind::Slash {
+            lexer.next_token();
+            SegmentSpec::parse(lexer)?
+        } else {
+            SegmentSpec::default()
+        };
+        Some(Segment {loc, end: lexer.loc(), value, size, spec})
+    }
+}
+
+impl<T> ToSpan for Segment<T> {
+    fn to_span(&self) -> Span {
+        Span {start: self.loc.clone(), end: self.end.clone()}
+    }
+}
+
+#[derive(Clone)]
+pub enum PatternKind {
+    Wildcard,
+    Var(Token),
     Number(usize),
+    Bool(bool),
+    Atom(String),
+    Tuple(Vec<Pattern>),
+    // #{key := value, ...} matches the maps that have at least these keys
+    Map(Vec<(Pattern, Pattern)>),
+    Binary(Vec<Segment<Pattern>>),
+    // [a, b | tail], the tail is the empty list when it's omitted
+    List {
+        elements: Vec<Pattern>,
+        tail: Option<Box<Pattern>>,
+    },
+    // ok(x) matches the value made by the constructor of a union type
+    Constructor {
+        name: Token,
+        args: Vec<Pattern>,
+    },
+}
+
+#[derive(Clone)]
+pub struct Pattern {
+    pub loc: Loc,
+    // Right after the last character of the pattern
+    pub end: Loc,
+    pub kind: PatternKind,
+}
+
+impl ToSpan for Pattern {
+    fn to_span(&self) -> Span {
+        Span {start: self.loc.clone(), end: self.end.clone()}
+    }
+}
+
+impl Pattern {
+    // The variables that the pattern binds or compares with
+    pub fn vars(&self, vars: &mut Vec<String>) {
+        match &self.kind {
+            PatternKind::Wildcard | PatternKind::Number(_) | PatternKind::Bool(_) | PatternKind::Atom(_) => {}
+            PatternKind::Var(name) => {
+                if !vars.contains(&name.text) {
+                    vars.push(name.text.clone());
+                }
+            }
+            PatternKind::Tuple(elements) => {
+                for element in elements.iter() {
+                    element.vars(vars);
+                }
+            }
+            PatternKind::Map(pairs) => {
+                for (_, value) in pairs.iter() {
+                    value.vars(vars);
+                }
+            }
+            PatternKind::Binary(segments) => {
+                for segment in segments.iter() {
+                    segment.value.vars(vars);
+                }
+            }
+            PatternKind::List{elements, tail} => {
+                for element in elements.iter().chain(tail.iter().map(|tail| &**tail)) {
+                    element.vars(vars);
+                }
+            }
+            PatternKind::Constructor{args, ..} => {
+                for arg in args.iter() {
+                    arg.vars(vars);
+                }
+            }
+        }
+    }
+
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let token = lexer.expect_tokens(&[
+            TokenKind::Ident,
+            TokenKind::Number,
+            TokenKind::Atom,
+            TokenKind::True,
+            TokenKind::False,
+            TokenKind::OpenCurly,
+            TokenKind::Hash,
+            TokenKind::DoubleLess,
+            TokenKind::OpenBracket,
+        ])?;
+        let loc = token.loc.clone();
+        match token.kind {
+            TokenKind::Ident if token.text == "_" => Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Wildcard}),
+            TokenKind::Ident if lexer.peek_token().kind == TokenKind::OpenParen => {
+                lexer.next_token();
+                let args = parse_list(lexer, TokenKind::ClosedParen, Pattern::parse)?;
+                Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Constructor {name: token, args}})
+            }
+            TokenKind::Ident => Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Var(token)}),
+            TokenKind::Number => {
+                match token.text.parse::<usize>() {
+                    Ok(number) => Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Number(number)}),
+                    Err(err) => {
+                        report!(&token, "ERROR", E0002, "Could not parse number: {err}");
+                        None
+                    }
+                }
+            }
+            TokenKind::Atom => Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Atom(token.text)}),
+            TokenKind::True => Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Bool(true)}),
+            TokenKind::False => Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Bool(false)}),
+            TokenKind::OpenCurly => {
+                let elements = parse_list(lexer, TokenKind::ClosedCurly, Pattern::parse)?;
+                Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Tuple(elements)})
+            }
+            TokenKind::Hash => {
+                lexer.expect_tokens(&[TokenKind::OpenCurly])?;
+                let pairs = parse_list(lexer, TokenKind::ClosedCurly, Pattern::parse_map_pair)?;
+                Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Map(pairs)})
+            }
+            TokenKind::DoubleLess => {
+                let segments = parse_list(lexer, TokenKind::DoubleGreater, Pattern::parse_segment)?;
+                for (i, segment) in segments.iter().enumerate() {
+                    let is_last = i + 1 >= segments.len();
+                    if segment.size.is_none() && segment.spec.default_size().is_none() && !is_last {
+                        report!(&segment, "ERROR", E0028, "Only the last segment of a binary pattern may have no size");
+                        return None
+                    }
+                }
+                Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::Binary(segments)})
+            }
+            TokenKind::OpenBracket => {
+                let (elements, tail) = parse_list_literal(lexer, Pattern::parse)?;
+                Some(Pattern {loc, end: lexer.loc(), kind: PatternKind::List {elements, tail}})
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn parse_segment(lexer: &mut Lexer) -> Option<Segment<Self>> {
+        let segment = Segment::parse(lexer, Self::parse)?;
+        // bs_match only knows the fixed sizes
+        match &segment.size {
+            Some(pattern @ Pattern{kind: PatternKind::Number(size), ..}) => match size.checked_mul(segment.spec.unit()) {
+                Some(bits) if segment.spec.kind == SegmentKind::Integer && bits > 64 => {
+                    report!(pattern, "ERROR", E0028, "Integer segments of binary patterns may have at most 64 bits");
+                    return None
+                }
+                Some(bits) if bits <= MAX_SEGMENT_BITS => {}
+                _ => {
+                    report!(pattern, "ERROR", E0028, "Segments of binary patterns may have at most {MAX_SEGMENT_BITS} bits");
+                    return None
+                }
+            }
+            Some(size) => {
+                report!(&size, "ERROR", E0028, "Sizes of the segments of binary patterns must be numbers");
+                return None
+            }
+            None => {}
+        }
+        match segment.spec.kind {
+            SegmentKind::Float => {
+                report!(&segment, "ERROR", E0028, "Float segments are not supported in binary patterns");
+                None
+            }
+            _ if !matches!(segment.value.kind, PatternKind::Wildcard | PatternKind::Var(_) | PatternKind::Number(_)) => {
+                report!(&segment.value, "ERROR", E0028, "Segments of binary patterns may only match variables and numbers");
+                None
+            }
+            _ => Some(segment),
+        }
+    }
+
+    fn parse_map_pair(lexer: &mut Lexer) -> Option<(Self, Self)> {
+        let key = Self::parse(lexer)?;
+        // get_map_elements looks the keys up by value, so they must be known upfront
+        if !matches!(key.kind, PatternKind::Number(_) | PatternKind::Bool(_) | PatternKind::Atom(_)) {
+            report!(&key, "ERROR", E0030, "Keys of map patterns must be numbers, booleans or atoms");
+            return None
+        }
+        lexer.expect_tokens(&[TokenKind::ColonEquals])?;
+        let value = Self::parse(lexer)?;
+        Some((key, value))
+    }
+}
+
+// pattern [when guard] -> body
+#[derive(Clone)]
+pub struct MatchClause {
+    pub pattern: Pattern,
+    pub guard: Option<Expr>,
+    pub body: Expr,
+}
+
+impl MatchClause {
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let pattern = Pattern::parse(lexer)?;
+        Self::parse_after_pattern(lexer, pattern)
+    }
+
+    // Parses the rest of the clause, when its pattern is already parsed
+    fn parse_after_pattern(lexer: &mut Lexer, pattern: Pattern) -> Option<Self> {
+        let guard = if lexer.peek_token().kind == TokenKind::When {
+            lexer.next_token();
+            Some(Expr::parse(lexer)?)
+        } else {
+            None
+        };
+        lexer.expect_tokens(&[TokenKind::Arrow])?;
+        let body = Expr::parse(lexer)?;
+        Some(MatchClause {pattern, guard, body})
+    }
+
+    // Parses the clauses separated with semi-colons until one of the terminators.
+    // The terminator is consumed and returned.
+    fn parse_clauses(lexer: &mut Lexer, terminators: &[TokenKind]) -> Option<(Vec<Self>, Token)> {
+        let mut clauses = Vec::new();
+        let mut expected = vec![TokenKind::SemiColon];
+        expected.extend(terminators);
+        loop {
+            clauses.push(Self::parse(lexer)?);
+            let token = lexer.expect_tokens(&expected)?;
+            if token.kind != TokenKind::SemiColon {
+                return Some((clauses, token))
+            }
+        }
+    }
+}
+
+// class:pattern [when guard] -> body
+#[derive(Clone)]
+pub struct CatchClause {
+    // Matches the atom `throw`, `error` or `exit`
+    pub class: Pattern,
+    pub clause: MatchClause,
+}
+
+impl CatchClause {
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let mut pattern = Pattern::parse(lexer)?;
+        // Just like in Erlang the class is optional and defaults to `throw`
+        let class = if lexer.peek_token().kind == TokenKind::Colon {
+            lexer.next_token();
+            let class = match pattern.kind {
+                PatternKind::Wildcard => pattern,
+                PatternKind::Var(ref name) if ["throw", "error", "exit"].contains(&name.text.as_str()) => {
+                    Pattern {loc: pattern.loc, end: pattern.end, kind: PatternKind::Atom(name.text.clone())}
+                }
+                PatternKind::Atom(ref name) if ["throw", "error", "exit"].contains(&name.as_str()) => pattern,
+                _ => {
+                    report!(pattern, "ERROR", E0032, "Unknown class of exceptions. Expected throw, error, exit or _");
+                    return None
+                }
+            };
+            pattern = Pattern::parse(lexer)?;
+            class
+        } else {
+            Pattern {loc: pattern.loc.clone(), end: pattern.loc.clone(), kind: PatternKind::Atom("throw".to_string())}
+        };
+        let clause = MatchClause::parse_after_pattern(lexer, pattern)?;
+        Some(CatchClause {class, clause})
+    }
+}
+
+// key => value puts the key into the map, key := value updates the existing key
+#[derive(Clone)]
+pub struct MapPair {
+    pub key: Expr,
+    pub value: Expr,
+    pub exact: bool,
+}
+
+impl MapPair {
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let key = Expr::parse(lexer)?;
+        let exact = lexer.expect_tokens(&[TokenKind::FatArrow, TokenKind::ColonEquals])?.kind == TokenKind::ColonEquals;
+        let value = Expr::parse(lexer)?;
+        Some(MapPair {key, value, exact})
+    }
+}
+
+#[derive(Clone)]
+pub struct After {
+    pub timeout: Box<Expr>,
+    pub body: Box<Expr>,
+}
+
+#[derive(Clone)]
+pub enum ExprKind {
+    Number(i128),
+    Float(f64),
+    Bool(bool),
+    Atom(String),
     Var(Token),
+    Tuple(Vec<Expr>),
     Binop(Binop),
+    Not(Box<Expr>),
+    Send {
+        pid: Box<Expr>,
+        msg: Box<Expr>,
+    },
+    Call {
+        name: Token,
+        args: Vec<Expr>,
+    },
+    Case {
+        subject: Box<Expr>,
+        clauses: Vec<MatchClause>,
+    },
+    Receive {
+        clauses: Vec<MatchClause>,
+        after: Option<After>,
+    },
+    // name{field: value, ...} constructs a new record,
+    // name{base | field: value, ...} updates the fields of the base one
+    Record {
+        name: Token,
+        base: Option<Box<Expr>>,
+        fields: Vec<(Token, Expr)>,
+    },
+    // record.field
+    Field {
+        record: Box<Expr>,
+        field: Token,
+        // The type checker figures out which record it is
+        record_name: RefCell<Option<String>>,
+    },
+    // #{key => value, ...} constructs a new map, base#{key => value, ...} updates the base one
+    Map {
+        base: Option<Box<Expr>>,
+        pairs: Vec<MapPair>,
+    },
+    Binary(Vec<Segment<Expr>>),
+    // [a, b | tail], the tail is the empty list when it's omitted
+    List {
+        elements: Vec<Expr>,
+        tail: Option<Box<Expr>>,
+    },
+    // [body || pattern <- list, filter, ...]
+    // Replaced with the calls of the hidden functions before the type checking.
+    Comprehension {
+        body: Box<Expr>,
+        pattern: Pattern,
+        list: Box<Expr>,
+        filters: Vec<Expr>,
+    },
+    // try body [of clauses] catch catches end
+    Try {
+        body: Box<Expr>,
+        clauses: Vec<MatchClause>,
+        catches: Vec<CatchClause>,
+    },
+}
+
+#[derive(Clone)]
+pub struct Expr {
+    pub loc: Loc,
+    // Right after the last character of the expression
+    pub end: Loc,
+    pub kind: ExprKind,
+}
+
+impl ToSpan for Expr {
+    fn to_span(&self) -> Span {
+        Span {start: self.loc.clone(), end: self.end.clone()}
+    }
+}
+
+// Parses the rest of `[a, b | tail]`. The opening bracket is expected to be
+// consumed by the caller.
+fn parse_list_literal<T>(lexer: &mut Lexer, parse_item: fn(&mut Lexer) -> Option<T>) -> Option<(Vec<T>, Option<Box<T>>)> {
+    let mut elements = Vec::new();
+    if lexer.peek_token().kind == TokenKind::ClosedBracket {
+        lexer.next_token();
+        return Some((elements, None))
+    }
+    loop {
+        elements.push(parse_item(lexer)?);
+        let token = lexer.expect_tokens(&[TokenKind::Comma, TokenKind::Bar, TokenKind::ClosedBracket])?;
+        match token.kind {
+            TokenKind::Comma => {}
+            TokenKind::Bar => {
+                let tail = parse_item(lexer)?;
+                lexer.expect_tokens(&[TokenKind::ClosedBracket])?;
+                return Some((elements, Some(Box::new(tail))))
+            }
+            TokenKind::ClosedBracket => return Some((elements, None)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+// Parses comma separated items until the closing token. The opening token
+// is expected to be consumed by the caller.
+fn parse_list<T>(lexer: &mut Lexer, closing: TokenKind, parse_item: fn(&mut Lexer) -> Option<T>) -> Option<Vec<T>> {
+    let mut items = Vec::new();
+    if lexer.peek_token().kind == closing {
+        lexer.next_token();
+        return Some(items)
+    }
+    loop {
+        items.push(parse_item(lexer)?);
+        let token = lexer.expect_tokens(&[TokenKind::Comma, closing])?;
+        if token.kind == closing {
+            return Some(items)
+        }
+    }
 }
 
 impl Expr {
-    fn parse(lexer: &mut Lexer) -> Option<Self> {
-        let token = lexer.expect_tokens(&[TokenKind::Number, TokenKind::Ident])?;
+    fn parse_primary(lexer: &mut Lexer) -> Option<Self> {
+        let token = lexer.expect_tokens(&[
+            TokenKind::Number,
+            TokenKind::Ident,
+            TokenKind::Atom,
+            TokenKind::True,
+            TokenKind::False,
+            TokenKind::Not,
+            TokenKind::OpenParen,
+            TokenKind::OpenCurly,
+            TokenKind::Case,
+            TokenKind::Receive,
+            TokenKind::Try,
+            TokenKind::Hash,
+            TokenKind::DoubleLess,
+            TokenKind::OpenBracket,
+        ])?;
+        let loc = token.loc.clone();
         match token.kind {
-            TokenKind::Ident => Some(Expr::Var(token)),
+            TokenKind::Ident if lexer.peek_token().kind == TokenKind::OpenParen => {
+                lexer.next_token();
+                let args = parse_list(lexer, TokenKind::ClosedParen, Expr::parse)?;
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Call {name: token, args}})
+            }
+            TokenKind::Ident if lexer.peek_token().kind == TokenKind::OpenCurly => {
+                lexer.next_token();
+                Self::parse_record(lexer, token)
+            }
+            TokenKind::Ident => Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Var(token)}),
+            TokenKind::Atom => Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Atom(token.text)}),
+            TokenKind::OpenCurly => {
+                let elements = parse_list(lexer, TokenKind::ClosedCurly, Expr::parse)?;
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Tuple(elements)})
+            }
+            TokenKind::Case => {
+                let subject = Self::parse(lexer)?;
+                lexer.expect_tokens(&[TokenKind::Of])?;
+                let (clauses, _) = MatchClause::parse_clauses(lexer, &[TokenKind::End])?;
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Case {subject: Box::new(subject), clauses}})
+            }
+            TokenKind::Receive => {
+                let mut clauses = Vec::new();
+                let mut terminator = lexer.peek_token();
+                if terminator.kind == TokenKind::After {
+                    lexer.next_token();
+                } else {
+                    (clauses, terminator) = MatchClause::parse_clauses(lexer, &[TokenKind::After, TokenKind::End])?;
+                }
+                let after = if terminator.kind == TokenKind::After {
+                    let timeout = Self::parse(lexer)?;
+                    lexer.expect_tokens(&[TokenKind::Arrow])?;
+                    let body = Self::parse(lexer)?;
+                    lexer.expect_tokens(&[TokenKind::End])?;
+                    Some(After {timeout: Box::new(timeout), body: Box::new(body)})
+                } else {
+                    None
+                };
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Receive {clauses, after}})
+            }
+            TokenKind::OpenBracket if lexer.peek_token().kind != TokenKind::ClosedBracket => {
+                let first = Self::parse(lexer)?;
+                if lexer.peek_token().kind != TokenKind::DoubleBar {
+                    let mut elements = vec![first];
+                    let tail = match lexer.expect_tokens(&[TokenKind::Comma, TokenKind::Bar, TokenKind::ClosedBracket])?.kind {
+                        TokenKind::Comma => {
+                            let (rest, tail) = parse_list_literal(lexer, Self::parse)?;
+                            elements.extend(rest);
+                            tail
+                        }
+                        TokenKind::Bar => {
+                            let tail = Self::parse(lexer)?;
+                            lexer.expect_tokens(&[TokenKind::ClosedBracket])?;
+                            Some(Box::new(tail))
+                        }
+                        _ => None,
+                    };
+                    return Some(Expr {loc, end: lexer.loc(), kind: ExprKind::List {elements, tail}})
+                }
+                lexer.next_token();
+                let pattern = Pattern::parse(lexer)?;
+                lexer.expect_tokens(&[TokenKind::LeftArrow])?;
+                let list = Self::parse(lexer)?;
+                let mut filters = Vec::new();
+                while lexer.expect_tokens(&[TokenKind::Comma, TokenKind::ClosedBracket])?.kind == TokenKind::Comma {
+                    filters.push(Self::parse(lexer)?);
+                }
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Comprehension {body: Box::new(first), pattern, list: Box::new(list), filters}})
+            }
+            TokenKind::OpenBracket => {
+                lexer.next_token();
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::List {elements: vec![], tail: None}})
+            }
+            TokenKind::DoubleLess => {
+                // The values of the segments are primary expressions, because
+                // the slash and the colon have their own meaning there
+                let segments = parse_list(lexer, TokenKind::DoubleGreater, |lexer| Segment::parse(lexer, Self::parse_postfix))?;
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Binary(segments)})
+            }
+            TokenKind::Hash => {
+                lexer.expect_tokens(&[TokenKind::OpenCurly])?;
+                let pairs = parse_list(lexer, TokenKind::ClosedCurly, MapPair::parse)?;
+                if let Some(pair) = pairs.iter().find(|pair| pair.exact) {
+                    report!(&pair.key, "ERROR", E0031, "Only => is allowed when constructing a map. := updates the existing keys");
+                    return None
+                }
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Map {base: None, pairs}})
+            }
+            TokenKind::Try => {
+                let body = Self::parse(lexer)?;
+                let mut clauses = Vec::new();
+                if lexer.expect_tokens(&[TokenKind::Of, TokenKind::Catch])?.kind == TokenKind::Of {
+                    (clauses, _) = MatchClause::parse_clauses(lexer, &[TokenKind::Catch])?;
+                }
+                let mut catches = Vec::new();
+                loop {
+                    catches.push(CatchClause::parse(lexer)?);
+                    if lexer.expect_tokens(&[TokenKind::SemiColon, TokenKind::End])?.kind == TokenKind::End {
+                        break
+                    }
+                }
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Try {body: Box::new(body), clauses, catches}})
+            }
+            TokenKind::True => Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Bool(true)}),
+            TokenKind::False => Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Bool(false)}),
+            TokenKind::Not => {
+                let operand = Self::parse_postfix(lexer)?;
+                Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Not(Box::new(operand))})
+            }
+            TokenKind::Number if token.text.contains(['.', 'e', 'E']) => {
+                match token.text.parse::<f64>() {
+                    Ok(number) => Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Float(number)}),
+                    Err(err) => {
+                        report!(&token, "ERROR", E0002, "Could not parse float: {err}");
+                        None
+                    }
+                }
+            }
             TokenKind::Number => {
-                match token.text.parse::<usize>() {
-                    Ok(number) => Some(Expr::Number(number)),
+                match token.text.parse::<i128>() {
+                    Ok(number) => Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Number(number)}),
                     Err(err) => {
-                        report!(&token.loc, "ERROR", "Could not parse number: {err}");
+                        report!(&token, "ERROR", E0002, "Could not parse number: {err}");
                         None
                     }
                 }
             }
+            TokenKind::OpenParen => {
+                let expr = Self::parse(lexer)?;
+                lexer.expect_tokens(&[TokenKind::ClosedParen])?;
+                Some(expr)
+            }
             _ => unreachable!(),
         }
     }
+
+    // The opening curly is expected to be consumed by the caller
+    fn parse_record(lexer: &mut Lexer, name: Token) -> Option<Self> {
+        let loc = name.loc.clone();
+        let mut base = None;
+        let mut fields = Vec::new();
+        if lexer.peek_token().kind == TokenKind::ClosedCurly {
+            lexer.next_token();
+            return Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Record {name, base, fields}})
+        }
+        loop {
+            let expr = Self::parse(lexer)?;
+            if base.is_none() && fields.is_empty() && lexer.peek_token().kind == TokenKind::Bar {
+                lexer.next_token();
+                base = Some(Box::new(expr));
+                continue;
+            }
+            let field = match expr.kind {
+                ExprKind::Var(field) => field,
+                _ => {
+                    report!(&expr, "ERROR", E0039, "Expected the name of a field of record {name}", name = name.text);
+                    return None
+                }
+            };
+            lexer.expect_tokens(&[TokenKind::Colon])?;
+            fields.push((field, Self::parse(lexer)?));
+            if lexer.expect_tokens(&[TokenKind::Comma, TokenKind::ClosedCurly])?.kind == TokenKind::ClosedCurly {
+                return Some(Expr {loc, end: lexer.loc(), kind: ExprKind::Record {name, base, fields}})
+            }
+        }
+    }
+
+    // The subexpressions, including the guards and the bodies of the clauses
+    pub fn children(&self) -> Vec<&Expr> {
+        let mut children = Vec::new();
+        match &self.kind {
+            ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Atom(_) | ExprKind::Var(_) => {}
+            ExprKind::Tuple(elements) => children.extend(elements.iter()),
+            ExprKind::Binop(Binop{lhs, rhs, ..}) => children.extend([&**lhs, &**rhs]),
+            ExprKind::Not(operand) => children.push(&**operand),
+            ExprKind::Send{pid, msg} => children.extend([&**pid, &**msg]),
+            ExprKind::Call{args, ..} => children.extend(args.iter()),
+            ExprKind::Case{subject, clauses} => {
+                children.push(&**subject);
+                for clause in clauses.iter() {
+                    children.extend(clause.guard.iter());
+                    children.push(&clause.body);
+                }
+            }
+            ExprKind::Receive{clauses, after} => {
+                for clause in clauses.iter() {
+                    children.extend(clause.guard.iter());
+                    children.push(&clause.body);
+                }
+                if let Some(After{timeout, body}) = after {
+                    children.extend([&**timeout, &**body]);
+                }
+            }
+            ExprKind::Record{base, fields, ..} => {
+                children.extend(base.iter().map(|base| &**base));
+                children.extend(fields.iter().map(|(_, value)| value));
+            }
+            ExprKind::Field{record, ..} => children.push(&**record),
+            ExprKind::Map{base, pairs} => {
+                children.extend(base.iter().map(|base| &**base));
+                for pair in pairs.iter() {
+                    children.extend([&pair.key, &pair.value]);
+                }
+            }
+            ExprKind::Binary(segments) => {
+                for segment in segments.iter() {
+                    children.push(&segment.value);
+                    children.extend(segment.size.iter());
+                }
+            }
+            ExprKind::List{elements, tail} => {
+                children.extend(elements.iter());
+                children.extend(tail.iter().map(|tail| &**tail));
+            }
+            ExprKind::Comprehension{body, list, filters, ..} => {
+                children.extend([&**body, &**list]);
+                children.extend(filters.iter());
+            }
+            ExprKind::Try{body, clauses, catches} => {
+                children.push(&**body);
+                for clause in clauses.iter().chain(catches.iter().map(|catch| &catch.clause)) {
+                    children.extend(clause.guard.iter());
+                    children.push(&clause.body);
+                }
+            }
+        }
+        children
+    }
+
+    // The same as children(), but mutable
+    pub fn children_mut(&mut self) -> Vec<&mut Expr> {
+        let mut children = Vec::new();
+        match &mut self.kind {
+            ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Atom(_) | ExprKind::Var(_) => {}
+            ExprKind::Tuple(elements) => children.extend(elements.iter_mut()),
+            ExprKind::Binop(Binop{lhs, rhs, ..}) => children.extend([&mut **lhs, &mut **rhs]),
+            ExprKind::Not(operand) => children.push(&mut **operand),
+            ExprKind::Send{pid, msg} => children.extend([&mut **pid, &mut **msg]),
+            ExprKind::Call{args, ..} => children.extend(args.iter_mut()),
+            ExprKind::Case{subject, clauses} => {
+                children.push(&mut **subject);
+                for clause in clauses.iter_mut() {
+                    children.extend(clause.guard.iter_mut());
+                    children.push(&mut clause.body);
+                }
+            }
+            ExprKind::Receive{clauses, after} => {
+                for clause in clauses.iter_mut() {
+                    children.extend(clause.guard.iter_mut());
+                    children.push(&mut clause.body);
+                }
+                if let Some(After{timeout, body}) = after {
+                    children.extend([&mut **timeout, &mut **body]);
+                }
+            }
+            ExprKind::Record{base, fields, ..} => {
+                children.extend(base.iter_mut().map(|base| &mut **base));
+                children.extend(fields.iter_mut().map(|(_, value)| value));
+            }
+            ExprKind::Field{record, ..} => children.push(&mut **record),
+            ExprKind::Map{base, pairs} => {
+                children.extend(base.iter_mut().map(|base| &mut **base));
+                for pair in pairs.iter_mut() {
+                    children.extend([&mut pair.key, &mut pair.value]);
+                }
+            }
+            ExprKind::Binary(segments) => {
+                for segment in segments.iter_mut() {
+                    children.push(&mut segment.value);
+                    children.extend(segment.size.iter_mut());
+                }
+            }
+            ExprKind::List{elements, tail} => {
+                children.extend(elements.iter_mut());
+                children.extend(tail.iter_mut().map(|tail| &mut **tail));
+            }
+            ExprKind::Comprehension{body, list, filters, ..} => {
+                children.extend([&mut **body, &mut **list]);
+                children.extend(filters.iter_mut());
+            }
+            ExprKind::Try{body, clauses, catches} => {
+                children.push(&mut **body);
+                for clause in clauses.iter_mut().chain(catches.iter_mut().map(|catch| &mut catch.clause)) {
+                    children.extend(clause.guard.iter_mut());
+                    children.push(&mut clause.body);
+                }
+            }
+        }
+        children
+    }
+
+    // The patterns that are matched directly by this expression
+    pub fn patterns(&self) -> Vec<&Pattern> {
+        match &self.kind {
+            ExprKind::Case{clauses, ..} | ExprKind::Receive{clauses, ..} => clauses.iter().map(|clause| &clause.pattern).collect(),
+            ExprKind::Try{clauses, catches, ..} => {
+                let mut patterns: Vec<&Pattern> = clauses.iter().map(|clause| &clause.pattern).collect();
+                for catch in catches.iter() {
+                    patterns.extend([&catch.class, &catch.clause.pattern]);
+                }
+                patterns
+            }
+            ExprKind::Comprehension{pattern, ..} => vec![pattern],
+            _ => vec![],
+        }
+    }
+
+    // Accesses the fields of the primary expression or updates it, if it's a map
+    fn parse_postfix(lexer: &mut Lexer) -> Option<Self> {
+        let mut expr = Self::parse_primary(lexer)?;
+        loop {
+            match lexer.peek_token().kind {
+                TokenKind::Dot => {
+                    lexer.next_token();
+                    let field = lexer.expect_tokens(&[TokenKind::Ident])?;
+                    expr = Expr {
+                        loc: expr.loc.clone(),
+                        end: field.end.clone(),
+                        kind: ExprKind::Field {
+                            record: Box::new(expr),
+                            field,
+                            record_name: RefCell::new(None),
+                        },
+                    };
+                }
+                TokenKind::Hash => {
+                    lexer.next_token();
+                    lexer.expect_tokens(&[TokenKind::OpenCurly])?;
+                    let pairs = parse_list(lexer, TokenKind::ClosedCurly, MapPair::parse)?;
+                    expr = Expr {loc: expr.loc.clone(), end: lexer.loc(), kind: ExprKind::Map {base: Some(Box::new(expr)), pairs}};
+                }
+                _ => return Some(expr),
+            }
+        }
+    }
+
+    fn parse_binop(lexer: &mut Lexer, precedence: usize) -> Option<Self> {
+        if precedence > BinopKind::MAX_PRECEDENCE {
+            return Self::parse_postfix(lexer)
+        }
+
+        let mut lhs = Self::parse_binop(lexer, precedence + 1)?;
+        while let Some(kind) = binop_of_token(lexer.peek_token().kind) {
+            if kind.precedence() != precedence {
+                break
+            }
+            lexer.next_token();
+            let rhs = Self::parse_binop(lexer, precedence + 1)?;
+            lhs = Expr {
+                loc: lhs.loc.clone(),
+                end: rhs.end.clone(),
+                kind: ExprKind::Binop(Binop {
+                    kind,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                }),
+            };
+        }
+        Some(lhs)
+    }
+
+    pub fn parse(lexer: &mut Lexer) -> Option<Self> {
+        // `pid ! msg` has the lowest precedence and is right associative
+        let lhs = Self::parse_binop(lexer, 0)?;
+        if lexer.peek_token().kind == TokenKind::Bang {
+            lexer.next_token();
+            let rhs = Self::parse(lexer)?;
+            return Some(Expr {
+                loc: lhs.loc.clone(),
+                end: rhs.end.clone(),
+                kind: ExprKind::Send {
+                    pid: Box::new(lhs),
+                    msg: Box::new(rhs),
+                },
+            })
+        }
+        Some(lhs)
+    }
+}
+
+pub struct FuncClause {
+    pub params: Params,
+    pub guard: Option<Expr>,
+    pub body: Expr,
+}
+
+impl FuncClause {
+    // Everything after the name of the function
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let _ = lexer.expect_tokens(&[TokenKind::OpenParen])?;
+
+        let mut params = Params::default();
+        'parse_params: loop {
+            if let TokenKind::Hash | TokenKind::DoubleLess | TokenKind::OpenBracket = lexer.peek_token().kind {
+                let pattern = Pattern::parse(lexer)?;
+                let index = params.len();
+                let name = Token {
+                    kind: TokenKind::Ident,
+                    text: format!("#{index}"),
+                    loc: pattern.loc.clone(),
+                    end: pattern.loc.clone(),
+                };
+                let typ = match pattern.kind {
+                    PatternKind::Map(_) => Type::Map(None),
+                    // The clauses of the function share the type of the elements
+                    PatternKind::List{..} => Type::List(Some(Box::new(Type::Var(name.text.clone())))),
+                    _ => Type::Binary,
+                };
+                params.push(Param {name, typ, pattern: Some(pattern)});
+                continue;
+            }
+            let name = lexer.expect_tokens(&[TokenKind::Ident, TokenKind::Hash, TokenKind::DoubleLess, TokenKind::OpenBracket, TokenKind::ClosedParen])?;
+            if let Some(existing_param) = params.get(&name.text) {
+                report!(&name, "ERROR", E0010, "Redefinition of existing parameter {name}", name = name.text);
+                label!(&existing_param.name, "The existing parameter is defined here");
+                return None;
+            }
+            match name.kind {
+                TokenKind::Ident => {
+                    let typ = Type::parse(lexer)?;
+                    params.push(Param {name, typ, pattern: None});
+                },
+                TokenKind::ClosedParen => break 'parse_params,
+                _ => unreachable!()
+            }
+        }
+
+        let guard = if lexer.peek_token().kind == TokenKind::When {
+            lexer.next_token();
+            Some(Expr::parse(lexer)?)
+        } else {
+            None
+        };
+
+        let _ = lexer.expect_tokens(&[TokenKind::Equals])?;
+        let body = Expr::parse(lexer)?;
+        lexer.expect_tokens(&[TokenKind::SemiColon])?;
+
+        Some(FuncClause {params, guard, body})
+    }
 }
 
 pub struct Func {
     pub name: Token,
-    pub params: HashMap<String, Param>,
-    pub body: Expr,
+    pub clauses: Vec<FuncClause>,
+    // The private functions and the ones generated by the compiler can only
+    // be called locally
+    pub exported: bool,
 }
 
+impl Func {
+    pub fn arity(&self) -> usize {
+        self.clauses[0].params.len()
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub enum Type {
     Int,
+    Float,
+    Bool,
+    Atom,
+    Pid,
+    // The element types are not checked when the value comes from the
+    // outside, so None means the elements may be anything.
+    // {int, atom} or tuple
+    Tuple(Option<Vec<Type>>),
+    // fn(int, int) -> int or fun
+    Fun(Option<(Vec<Type>, Box<Type>)>),
+    // #{atom => int} or map
+    Map(Option<Box<(Type, Type)>>),
+    // [int] or list
+    List(Option<Box<Type>>),
+    // Bit strings included
+    Binary,
+    // The name of the record. The type checker makes sure it exists.
+    Record(String),
+    // The name of the union type
+    Union(String),
+    // Any term, the type of the values that come from the outside world, like messages
+    Any,
+    // 'a stands for any type, but the same one everywhere in the function
+    Var(String),
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Int => write!(f, "int"),
+            Self::Float => write!(f, "float"),
+            Self::Bool => write!(f, "bool"),
+            Self::Atom => write!(f, "atom"),
+            Self::Pid => write!(f, "pid"),
+            Self::Tuple(None) => write!(f, "tuple"),
+            Self::Tuple(Some(elements)) => {
+                write!(f, "{{")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{element}")?;
+                }
+                write!(f, "}}")
+            }
+            Self::Fun(None) => write!(f, "fun"),
+            Self::Fun(Some((params, ret))) => {
+                write!(f, "fn(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{param}")?;
+                }
+                write!(f, ") -> {ret}")
+            }
+            Self::Map(None) => write!(f, "map"),
+            Self::Map(Some(pair)) => write!(f, "#{{{key} => {value}}}", key = pair.0, value = pair.1),
+            Self::List(None) => write!(f, "list"),
+            Self::List(Some(element)) => write!(f, "[{element}]"),
+            Self::Binary => write!(f, "binary"),
+            Self::Record(name) | Self::Union(name) => write!(f, "{name}"),
+            Self::Any => write!(f, "any"),
+            Self::Var(name) => write!(f, "'{name}"),
+        }
+    }
 }
 
 impl Type {
+    // Collects the names of the type variables in the order of their appearance
+    pub fn vars(&self, vars: &mut Vec<String>) {
+        match self {
+            Self::Var(name) if !vars.contains(name) => vars.push(name.clone()),
+            Self::Tuple(Some(elements)) => for element in elements.iter() {
+                element.vars(vars)
+            },
+            Self::Fun(Some((params, ret))) => {
+                for param in params.iter() {
+                    param.vars(vars)
+                }
+                ret.vars(vars)
+            }
+            Self::Map(Some(pair)) => {
+                pair.0.vars(vars);
+                pair.1.vars(vars)
+            }
+            Self::List(Some(element)) => element.vars(vars),
+            _ => {}
+        }
+    }
+
+    // Replaces the type variables with the types produced by `f`
+    pub fn map_vars(&self, f: &mut impl FnMut(&str) -> Type) -> Type {
+        match self {
+            Self::Var(name) => f(name),
+            Self::Tuple(Some(elements)) => Self::Tuple(Some(elements.iter().map(|element| element.map_vars(f)).collect())),
+            Self::Fun(Some((params, ret))) => {
+                let params = params.iter().map(|param| param.map_vars(f)).collect();
+                Self::Fun(Some((params, Box::new(ret.map_vars(f)))))
+            }
+            Self::Map(Some(pair)) => Self::Map(Some(Box::new((pair.0.map_vars(f), pair.1.map_vars(f))))),
+            Self::List(Some(element)) => Self::List(Some(Box::new(element.map_vars(f)))),
+            _ => self.clone(),
+        }
+    }
+
+    // The names of the types are parsed as records, because the union types may be
+    // declared after they are used. Once the whole module is parsed, the names
+    // of the union types are fixed up here.
+    fn resolve_unions(&mut self, unions: &[String]) {
+        match self {
+            Self::Record(name) if unions.contains(name) => *self = Self::Union(name.clone()),
+            Self::Tuple(Some(elements)) => for element in elements.iter_mut() {
+                element.resolve_unions(unions)
+            },
+            Self::Fun(Some((params, ret))) => {
+                for param in params.iter_mut() {
+                    param.resolve_unions(unions)
+                }
+                ret.resolve_unions(unions)
+            }
+            Self::Map(Some(pair)) => {
+                pair.0.resolve_unions(unions);
+                pair.1.resolve_unions(unions)
+            }
+            Self::List(Some(element)) => element.resolve_unions(unions),
+            _ => {}
+        }
+    }
+
     fn parse(lexer: &mut Lexer) -> Option<Self> {
-        let ident = lexer.expect_tokens(&[TokenKind::Ident])?;
-        match ident.text.as_str() {
-            "int" => Some(Type::Int),
-            unknown => {
-                report!(&ident.loc, "ERROR", "Unknown type `{unknown}`");
-                None
+        let token = lexer.expect_type_tokens(&[TokenKind::Ident, TokenKind::TypeVar, TokenKind::OpenBracket, TokenKind::OpenCurly, TokenKind::Hash])?;
+        match token.kind {
+            TokenKind::Ident => {}
+            TokenKind::TypeVar => return Some(Type::Var(token.text)),
+            TokenKind::OpenBracket => {
+                let element = Type::parse(lexer)?;
+                lexer.expect_tokens(&[TokenKind::ClosedBracket])?;
+                return Some(Type::List(Some(Box::new(element))))
             }
+            TokenKind::OpenCurly => {
+                let elements = parse_list(lexer, TokenKind::ClosedCurly, Type::parse)?;
+                return Some(Type::Tuple(Some(elements)))
+            }
+            TokenKind::Hash => {
+                lexer.expect_tokens(&[TokenKind::OpenCurly])?;
+                let key = Type::parse(lexer)?;
+                lexer.expect_tokens(&[TokenKind::FatArrow])?;
+                let value = Type::parse(lexer)?;
+                lexer.expect_tokens(&[TokenKind::ClosedCurly])?;
+                return Some(Type::Map(Some(Box::new((key, value)))))
+            }
+            _ => unreachable!(),
+        }
+        match token.text.as_str() {
+            "fn" if lexer.peek_token().kind == TokenKind::OpenParen => {
+                lexer.next_token();
+                let params = parse_list(lexer, TokenKind::ClosedParen, Type::parse)?;
+                lexer.expect_tokens(&[TokenKind::Arrow])?;
+                let ret = Type::parse(lexer)?;
+                Some(Type::Fun(Some((params, Box::new(ret)))))
+            }
+            "int" => Some(Type::Int),
+            "float" => Some(Type::Float),
+            "bool" => Some(Type::Bool),
+            "atom" => Some(Type::Atom),
+            "pid" => Some(Type::Pid),
+            "tuple" => Some(Type::Tuple(None)),
+            "fun" => Some(Type::Fun(None)),
+            "map" => Some(Type::Map(None)),
+            "list" => Some(Type::List(None)),
+            "binary" => Some(Type::Binary),
+            "any" => Some(Type::Any),
+            // The records may be declared after they are used
+            _ => Some(Type::Record(token.text)),
         }
     }
 }
@@ -72,86 +1264,434 @@ impl Type {
 pub struct Param {
     pub name: Token,
     pub typ: Type,
-    pub index: usize,
+    // The map, binary or list pattern in place of the parameter. Such parameter has no name
+    // that could be referred to.
+    pub pattern: Option<Pattern>,
 }
 
+// The parameters of the function clause in the order of the arguments, so the
+// position of the parameter is the number of its x register on the entry to
+// the function. They can also be looked up by name.
+#[derive(Default)]
+pub struct Params {
+    params: Vec<Param>,
+    indices: HashMap<String, usize>,
+}
+
+impl Params {
+    // The name of the parameter must not be taken yet
+    pub fn push(&mut self, param: Param) {
+        self.indices.insert(param.name.text.clone(), self.params.len());
+        self.params.push(param);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Param> {
+        self.indices.get(name).map(|index| &self.params[*index])
+    }
+
+    pub fn len(&self) -> usize {
+        self.params.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Param> {
+        self.params.iter()
+    }
+
+    // The names can't be changed through it, so the index stays valid
+    pub fn types_mut(&mut self) -> impl Iterator<Item = &mut Type> {
+        self.params.iter_mut().map(|param| &mut param.typ)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.params.iter().map(|param| &param.name.text)
+    }
+}
+
+impl std::ops::Index<usize> for Params {
+    type Output = Param;
+
+    fn index(&self, index: usize) -> &Param {
+        &self.params[index]
+    }
+}
+
+pub struct Field {
+    pub name: Token,
+    pub typ: Type,
+}
+
+impl Field {
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let name = lexer.expect_tokens(&[TokenKind::Ident])?;
+        let typ = Type::parse(lexer)?;
+        Some(Field {name, typ})
+    }
+}
+
+// record name { field type, ... }
+// Compiled to a tuple tagged with the name of the record, just like in Erlang.
+pub struct Record {
+    pub name: Token,
+    pub fields: Vec<Field>,
+}
+
+impl Record {
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|field| field.name.text == name)
+    }
+
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let name = lexer.expect_tokens(&[TokenKind::Ident])?;
+        lexer.expect_tokens(&[TokenKind::OpenCurly])?;
+        let fields = parse_list(lexer, TokenKind::ClosedCurly, Field::parse)?;
+        for (i, field) in fields.iter().enumerate() {
+            if let Some(existing_field) = fields[..i].iter().find(|existing| existing.name.text == field.name.text) {
+                report!(&field.name, "ERROR", E0015, "Redefinition of existing field {name}", name = field.name.text);
+                label!(&existing_field.name, "The existing field is defined here");
+                return None;
+            }
+        }
+        Some(Record {name, fields})
+    }
+}
+
+pub struct Constructor {
+    pub name: Token,
+    pub fields: Vec<Type>,
+}
+
+// type name = ok(int) | error(atom) | none
+// The constructors without fields are compiled to atoms, and the rest to
+// tuples tagged with the name of the constructor.
+pub struct Union {
+    pub name: Token,
+    pub constructors: Vec<Constructor>,
+}
+
+impl Union {
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let name = lexer.expect_tokens(&[TokenKind::Ident])?;
+        lexer.expect_tokens(&[TokenKind::Equals])?;
+        let mut constructors: Vec<Constructor> = Vec::new();
+        loop {
+            let ctor_name = lexer.expect_tokens(&[TokenKind::Ident])?;
+            if let Some(existing) = constructors.iter().find(|existing| existing.name.text == ctor_name.text) {
+                report!(&ctor_name, "ERROR", E0013, "Redefinition of existing constructor {name}", name = ctor_name.text);
+                label!(&existing.name, "The existing constructor is defined here");
+                return None;
+            }
+            let fields = if lexer.peek_token().kind == TokenKind::OpenParen {
+                lexer.next_token();
+                parse_list(lexer, TokenKind::ClosedParen, Type::parse)?
+            } else {
+                vec![]
+            };
+            constructors.push(Constructor {name: ctor_name, fields});
+            if lexer.peek_token().kind != TokenKind::Bar {
+                break
+            }
+            lexer.next_token();
+        }
+        Some(Union {name, constructors})
+    }
+}
+
+// const name = value;
+// The value must be known at compile time. The uses of the constant are
+// replaced with it by the constant folding.
+pub struct Const {
+    pub name: Token,
+    pub value: Expr,
+}
+
+// The declarations are kept sorted by name, so everything that goes through
+// them, from the diagnostics to the bytes of the compiled module, comes out
+// the same on every run
 #[derive(Default)]
 pub struct Module {
-    pub funcs: HashMap<String, Func>,
+    pub funcs: BTreeMap<String, Func>,
+    pub records: BTreeMap<String, Record>,
+    pub unions: BTreeMap<String, Union>,
+    pub consts: BTreeMap<String, Const>,
 }
 
 impl Module {
+    // The union type the constructor of that name belongs to
+    pub fn find_constructor(&self, name: &str) -> Option<(&Union, &Constructor)> {
+        self.unions.values().find_map(|union| {
+            union.constructors.iter().find(|ctor| ctor.name.text == name).map(|ctor| (union, ctor))
+        })
+    }
+
+    fn resolve_unions(&mut self) {
+        let unions: Vec<String> = self.unions.keys().cloned().collect();
+        for record in self.records.values_mut() {
+            for field in record.fields.iter_mut() {
+                field.typ.resolve_unions(&unions);
+            }
+        }
+        for func in self.funcs.values_mut() {
+            for clause in func.clauses.iter_mut() {
+                for typ in clause.params.types_mut() {
+                    typ.resolve_unions(&unions);
+                }
+            }
+        }
+        for union in self.unions.values_mut() {
+            for ctor in union.constructors.iter_mut() {
+                for field in ctor.fields.iter_mut() {
+                    field.resolve_unions(&unions);
+                }
+            }
+        }
+    }
+
     pub fn parse(lexer: &mut Lexer) -> Option<Module> {
         let mut module = Module::default();
-        loop {
-            let name = lexer.expect_tokens(&[
-                TokenKind::Ident,
-                TokenKind::End
-            ])?;
-            match name.kind {
-                TokenKind::Ident => {
-                    // TODO: redefinition of the function should be allowed for function with different arity
-                    if let Some(existing_func) = module.funcs.get(&name.text) {
-                        report!(&name.loc, "ERROR", "Redefinition of existing function {name}", name = name.text);
-                        report!(&existing_func.name.loc, "INFO", "The existing function is defined here");
-                        return None;
+        let mut ok = true;
+        while lexer.peek_token().kind != TokenKind::EndOfInput {
+            let decl = if let Some(decl) = Decl::parse(lexer) {
+                decl
+            } else {
+                // The rest of the module is still checked, so all the syntax errors are reported at once
+                ok = false;
+                skip_to_next_decl(lexer);
+                continue;
+            };
+            match decl {
+                Decl::Record(record) => {
+                    if let Some(existing_record) = module.records.get(&record.name.text) {
+                        report!(&record.name, "ERROR", E0011, "Redefinition of existing record {name}", name = record.name.text);
+                        label!(&existing_record.name, "The existing record is defined here");
+                        ok = false;
+                        continue;
                     }
-
-                    let _ = lexer.expect_tokens(&[TokenKind::OpenParen])?;
-
-                    let mut params: HashMap<String, Param> = HashMap::new();
-                    'parse_params: loop {
-                        let name = lexer.expect_tokens(&[TokenKind::Ident, TokenKind::ClosedParen])?;
-                        if let Some(existing_param) = params.get(&name.text) {
-                            report!(&name.loc, "ERROR", "Redefinition of existing parameter {name}", name = name.text);
-                            report!(&existing_param.name.loc, "INFO", "The existing parameter is defined here");
-                            return None;
-                        }
-                        match name.kind {
-                            TokenKind::Ident => {
-                                let typ = Type::parse(lexer)?;
-                                let index = params.len();
-                                params.insert(name.text.clone(), Param {name, typ, index});
-                            },
-                            TokenKind::ClosedParen => break 'parse_params,
-                            _ => unreachable!()
+                    module.records.insert(record.name.text.clone(), record);
+                }
+                Decl::Union(union) => {
+                    if let Some(existing_union) = module.unions.get(&union.name.text) {
+                        report!(&union.name, "ERROR", E0012, "Redefinition of existing type {name}", name = union.name.text);
+                        label!(&existing_union.name, "The existing type is defined here");
+                        ok = false;
+                        continue;
+                    }
+                    let mut redefined = false;
+                    for ctor in union.constructors.iter() {
+                        if let Some((_, existing_ctor)) = module.find_constructor(&ctor.name.text) {
+                            report!(&ctor.name, "ERROR", E0013, "Redefinition of existing constructor {name}", name = ctor.name.text);
+                            label!(&existing_ctor.name, "The existing constructor is defined here");
+                            redefined = true;
                         }
                     }
-
-                    let _ = lexer.expect_tokens(&[TokenKind::Equals])?;
-                    let lhs = Expr::parse(lexer)?;
-                    let token = lexer.expect_tokens(&[
-                        TokenKind::SemiColon,
-                        TokenKind::Plus,
-                        TokenKind::Minus,
-                    ])?;
-                    match token.kind {
-                        TokenKind::SemiColon => {
-                            module.funcs.insert(name.text.clone(), Func {
-                                name,
-                                params,
-                                body: lhs
-                            });
+                    if redefined {
+                        ok = false;
+                        continue;
+                    }
+                    module.unions.insert(union.name.text.clone(), union);
+                }
+                Decl::Const(decl) => {
+                    if let Some(existing_const) = module.consts.get(&decl.name.text) {
+                        report!(&decl.name, "ERROR", E0014, "Redefinition of existing constant {name}", name = decl.name.text);
+                        label!(&existing_const.name, "The existing constant is defined here");
+                        ok = false;
+                        continue;
+                    }
+                    module.consts.insert(decl.name.text.clone(), *decl);
+                }
+                Decl::Clause{name, private, clause} => {
+                    // Defining a function with the same name again adds one more clause to it
+                    if let Some(existing_func) = module.funcs.get_mut(&name.text) {
+                        // TODO: redefinition of the function should be allowed for function with different arity
+                        if existing_func.arity() != clause.params.len() {
+                            report!(&name, "ERROR", E0009, "Redefinition of existing function {name} with a different arity", name = name.text);
+                            label!(&existing_func.name, "The existing function is defined here");
+                            ok = false;
+                            continue;
                         }
-                        TokenKind::Plus | TokenKind::Minus => {
-                            let rhs = Expr::parse(lexer)?;
-                            module.funcs.insert(name.text.clone(), Func {
-                                name,
-                                params,
-                                body: Expr::Binop(Binop {
-                                    kind: binop_of_token(token.kind).expect("binop kind"),
-                                    lhs: Box::new(lhs),
-                                    rhs: Box::new(rhs),
-                                })
-                            });
-                            lexer.expect_tokens(&[TokenKind::SemiColon])?;
+                        if existing_func.exported == private {
+                            report!(&name, "ERROR", E0033, "Either all the clauses of function {name} are private or none of them", name = name.text);
+                            label!(&existing_func.name, "The first clause is defined here");
+                            ok = false;
+                            continue;
                         }
-                        _ => unreachable!(),
+                        existing_func.clauses.push(*clause);
+                    } else {
+                        module.funcs.insert(name.text.clone(), Func {
+                            name,
+                            clauses: vec![*clause],
+                            exported: !private,
+                        });
                     }
                 }
-                TokenKind::End => return Some(module),
-                _ => unreachable!(),
             }
         }
+        if !ok {
+            return None
+        }
+        module.resolve_unions();
+        Some(module)
+    }
+}
+
+// The top level declaration as it is written. Putting it into the module is
+// where the redefinitions are found.
+enum Decl {
+    Record(Record),
+    Union(Union),
+    Const(Box<Const>),
+    // One clause of the function with its name
+    Clause{name: Token, private: bool, clause: Box<FuncClause>},
+}
+
+impl Decl {
+    fn parse(lexer: &mut Lexer) -> Option<Self> {
+        let name = lexer.expect_tokens(&[
+            TokenKind::Ident,
+            TokenKind::Record,
+            TokenKind::Type,
+            TokenKind::Const,
+            TokenKind::Private,
+        ])?;
+        match name.kind {
+            TokenKind::Record => Some(Decl::Record(Record::parse(lexer)?)),
+            TokenKind::Type => Some(Decl::Union(Union::parse(lexer)?)),
+            TokenKind::Const => {
+                let name = lexer.expect_tokens(&[TokenKind::Ident])?;
+                lexer.expect_tokens(&[TokenKind::Equals])?;
+                let value = Expr::parse(lexer)?;
+                lexer.expect_tokens(&[TokenKind::SemiColon])?;
+                Some(Decl::Const(Box::new(Const {name, value})))
+            }
+            TokenKind::Private => {
+                let name = lexer.expect_tokens(&[TokenKind::Ident])?;
+                Some(Decl::Clause{name, private: true, clause: Box::new(FuncClause::parse(lexer)?)})
+            }
+            TokenKind::Ident => Some(Decl::Clause{name, private: false, clause: Box::new(FuncClause::parse(lexer)?)}),
+            _ => unreachable!(),
+        }
+    }
+}
+
+// The keywords start the declarations anywhere. The names of the functions
+// are only taken for the start of their clauses at the start of the line
+// outside of any brackets.
+fn starts_decl(token: &Token, depth: isize) -> bool {
+    match token.kind {
+        TokenKind::EndOfInput | TokenKind::Record | TokenKind::Type | TokenKind::Const | TokenKind::Private => true,
+        TokenKind::Ident => depth == 0 && token.loc.col == 1,
+        _ => false,
+    }
+}
+
+// How the token changes the nesting of the brackets and the blocks
+fn nesting(kind: TokenKind) -> isize {
+    match kind {
+        TokenKind::Case | TokenKind::Receive | TokenKind::Try | TokenKind::OpenParen | TokenKind::OpenCurly |
+        TokenKind::OpenBracket | TokenKind::DoubleLess => 1,
+        TokenKind::End | TokenKind::ClosedParen | TokenKind::ClosedCurly | TokenKind::ClosedBracket |
+        TokenKind::DoubleGreater => -1,
+        _ => 0,
+    }
+}
+
+// Skips the rest of the broken declaration, up to the `;` that ends it or
+// the start of the next one. The `;`s between the clauses of `case` and the
+// like don't end it, even if the error is inside the block.
+fn skip_to_next_decl(lexer: &mut Lexer) {
+    let mut depth = 0;
+    loop {
+        let token = lexer.peek_token();
+        if starts_decl(&token, depth) {
+            return
+        }
+        let kind = lexer.next_token().kind;
+        if kind == TokenKind::SemiColon && depth == 0 && !ends_block(lexer.clone()) {
+            return
+        }
+        depth = (depth + nesting(kind)).max(0);
+    }
+}
+
+// Whether the `end` of the block comes before the next `;` of the same depth
+fn ends_block(mut lexer: Lexer) -> bool {
+    let mut depth = 0;
+    loop {
+        let token = lexer.next_token();
+        let kind = token.kind;
+        match kind {
+            _ if starts_decl(&token, depth) => return false,
+            TokenKind::SemiColon if depth == 0 => return false,
+            TokenKind::End if depth == 0 => return true,
+            _ => depth = (depth + nesting(kind)).max(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diag;
+
+    fn parse_errors(source: &str) -> Vec<(usize, usize)> {
+        let content: Vec<char> = source.chars().collect();
+        let mut lexer = Lexer::new(&content, "test.boom".to_string());
+        assert!(Module::parse(&mut lexer).is_none());
+        diag::take_diagnostics().reports.iter().map(|diagnostic| (diagnostic.span.start.row, diagnostic.span.start.col)).collect()
+    }
+
+    #[test]
+    fn recovery_on_semicolon() {
+        let source = "\
+inc(x int) = x + ;
+broken() = (1 +
+    ) + foo(
+x, y);
+ok() = case 1 of
+    1 -> (;
+    _ -> 2
+end;
+bad(x int = x;
+";
+        assert_eq!(parse_errors(source), [(1, 18), (3, 5), (6, 11), (9, 11)]);
+    }
+
+    #[test]
+    fn recovery_on_keyword() {
+        let source = "\
+inc(x int) = x +
+record point { x int, y int }
+const ONE = ;
+half(x int) = (x / 2
+double(x int) = x * ;
+triple(x int) = x * 3;
+";
+        assert_eq!(parse_errors(source), [(2, 1), (3, 13), (5, 1), (5, 21)]);
+    }
+
+    #[test]
+    fn oversized_segments() {
+        assert_eq!(parse_errors("f(<<a:9223372036854775807/binary>>) = 1;\n"), [(1, 7)]);
+        assert_eq!(parse_errors("f(<<a:576460752303423488/bits>>) = 1;\n"), [(1, 7)]);
+        assert_eq!(parse_errors("f(<<a:65>>) = 1;\n"), [(1, 7)]);
+    }
+
+    #[test]
+    fn expression_spans() {
+        let content: Vec<char> = "p.x + foo(1, {2, 3}) ! #{a => 1}".chars().collect();
+        let mut lexer = Lexer::new(&content, "test.boom".to_string());
+        let expr = Expr::parse(&mut lexer).unwrap();
+        let (pid, msg) = match &expr.kind {
+            ExprKind::Send{pid, msg} => (pid, msg),
+            _ => panic!("expected a send"),
+        };
+        let call = match &pid.kind {
+            ExprKind::Binop(Binop{rhs, ..}) => rhs,
+            _ => panic!("expected a binop"),
+        };
+        let span = |expr: &Expr| (expr.loc.col, expr.end.col);
+        assert_eq!(span(&expr), (1, 33));
+        assert_eq!(span(pid), (1, 21));
+        assert_eq!(span(call), (7, 21));
+        assert_eq!(span(msg), (24, 33));
     }
 }
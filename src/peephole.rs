@@ -0,0 +1,210 @@
+use ir::{Instr, Operand};
+use std::collections::HashMap;
+
+// The code generator emits the straightforward instructions for each
+// expression without looking around. This pass cleans up after it by looking
+// at the neighbouring instructions. The rewrites are repeated until none of
+// them changes anything, since each one may open up the others.
+pub fn optimize(mut code: Vec<Instr>) -> Vec<Instr> {
+    loop {
+        let mut changed = false;
+        changed |= remove_self_moves(&mut code);
+        changed |= place_results(&mut code);
+        changed |= thread_jumps(&mut code);
+        changed |= remove_fallthrough_jumps(&mut code);
+        changed |= remove_unreachable(&mut code);
+        if !changed {
+            return code
+        }
+    }
+}
+
+// move x1 x1
+fn remove_self_moves(code: &mut Vec<Instr>) -> bool {
+    let len = code.len();
+    code.retain(|instr| !matches!(instr, Instr::Move{src, dst} if src == dst));
+    code.len() != len
+}
+
+// The result of the function is put right into x0 instead of being moved there:
+//
+//     gc_bif2 '+' x0 x1 x2         gc_bif2 '+' x0 x1 x0
+//     move x2 x0              =>   return
+//     return
+//
+// Nothing reads the registers after the return, and the stack frame is gone
+// after deallocate, so the original destination is not missed.
+fn place_results(code: &mut Vec<Instr>) -> bool {
+    let mut changed = false;
+    let mut i = 1;
+    while i + 1 < code.len() {
+        let returns = match &code[i + 1] {
+            Instr::Return => true,
+            Instr::Deallocate(_) => matches!(code.get(i + 2), Some(Instr::Return)),
+            _ => false,
+        };
+        if let (true, Instr::Move{src, dst: Operand::X(0)}) = (returns, &code[i]) {
+            let src = src.clone();
+            if let Some(dst) = code[i - 1].dst_mut() {
+                if *dst == src {
+                    *dst = Operand::X(0);
+                    code.remove(i);
+                    changed = true;
+                    continue
+                }
+            }
+        }
+        i += 1;
+    }
+    changed
+}
+
+// The jumps and the tests that go to a label followed by a jump go straight
+// to the final destination:
+//
+//     is_integer f(3) x0           is_integer f(7) x0
+//     ...                     =>   ...
+//     label 3                      label 3
+//     jump f(7)                    jump f(7)
+fn thread_jumps(code: &mut [Instr]) -> bool {
+    let mut jumps = HashMap::new();
+    for (i, instr) in code.iter().enumerate() {
+        if let Instr::Label(label) = instr {
+            if let Some(Instr::Jump(target)) = code[i + 1..].iter().find(|instr| !matches!(instr, Instr::Label(_))) {
+                jumps.insert(*label, *target);
+            }
+        }
+    }
+    // The loops made of jumps are left alone
+    let resolve = |label: u32| {
+        let mut visited = vec![label];
+        let mut target = label;
+        while let Some(next) = jumps.get(&target) {
+            if visited.contains(next) {
+                return label
+            }
+            visited.push(*next);
+            target = *next;
+        }
+        target
+    };
+
+    let mut changed = false;
+    for instr in code.iter_mut() {
+        for label in instr.targets_mut() {
+            let target = resolve(*label);
+            if target != *label {
+                *label = target;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// jump f(3)
+// label 3
+fn remove_fallthrough_jumps(code: &mut Vec<Instr>) -> bool {
+    let mut changed = false;
+    let mut i = 0;
+    while i < code.len() {
+        if let Instr::Jump(target) = code[i] {
+            let falls_through = code[i + 1..].iter()
+                .take_while(|instr| matches!(instr, Instr::Label(_)))
+                .any(|instr| *instr == Instr::Label(target));
+            if falls_through {
+                code.remove(i);
+                changed = true;
+                continue
+            }
+        }
+        i += 1;
+    }
+    changed
+}
+
+// Only a label can be reached after an instruction that never falls through
+fn remove_unreachable(code: &mut Vec<Instr>) -> bool {
+    let len = code.len();
+    let mut reachable = true;
+    code.retain(|instr| {
+        if let Instr::Label(_) | Instr::IntCodeEnd = instr {
+            reachable = true;
+            return true
+        }
+        let keep = reachable;
+        reachable = reachable && !instr.is_terminal();
+        keep
+    });
+    code.len() != len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ir::OpCode;
+
+    fn is_integer(fail: u32) -> Instr {
+        Instr::Test{op: OpCode::IsInteger, fail, args: vec![Operand::X(0)]}
+    }
+
+    #[test]
+    fn self_moves() {
+        let mut code = vec![Instr::Move{src: Operand::X(1), dst: Operand::X(1)}, Instr::Move{src: Operand::X(1), dst: Operand::X(0)}, Instr::Return];
+        assert!(remove_self_moves(&mut code));
+        assert!(code == [Instr::Move{src: Operand::X(1), dst: Operand::X(0)}, Instr::Return]);
+    }
+
+    #[test]
+    fn results() {
+        let mut code = vec![
+            Instr::GcBif2{fail: 0, live: 2, bif: 1, lhs: Operand::X(0), rhs: Operand::X(1), dst: Operand::X(2)},
+            Instr::Move{src: Operand::X(2), dst: Operand::X(0)},
+            Instr::Deallocate(0),
+            Instr::Return,
+        ];
+        assert!(place_results(&mut code));
+        assert!(code == [
+            Instr::GcBif2{fail: 0, live: 2, bif: 1, lhs: Operand::X(0), rhs: Operand::X(1), dst: Operand::X(0)},
+            Instr::Deallocate(0),
+            Instr::Return,
+        ]);
+    }
+
+    #[test]
+    fn results_of_other_registers() {
+        let mut code = vec![
+            Instr::GcBif2{fail: 0, live: 2, bif: 1, lhs: Operand::X(0), rhs: Operand::X(1), dst: Operand::X(2)},
+            Instr::Move{src: Operand::X(1), dst: Operand::X(0)},
+            Instr::Return,
+        ];
+        assert!(!place_results(&mut code));
+    }
+
+    #[test]
+    fn jumps() {
+        let mut code = vec![is_integer(3), Instr::Return, Instr::Label(3), Instr::Jump(5), Instr::Label(5), Instr::Jump(7)];
+        assert!(thread_jumps(&mut code));
+        assert!(code == [is_integer(7), Instr::Return, Instr::Label(3), Instr::Jump(7), Instr::Label(5), Instr::Jump(7)]);
+    }
+
+    #[test]
+    fn jump_loops() {
+        let mut code = vec![is_integer(3), Instr::Label(3), Instr::Jump(5), Instr::Label(5), Instr::Jump(3)];
+        assert!(!thread_jumps(&mut code));
+    }
+
+    #[test]
+    fn fallthrough_jumps() {
+        let mut code = vec![is_integer(5), Instr::Jump(3), Instr::Label(2), Instr::Label(3), Instr::Return];
+        assert!(remove_fallthrough_jumps(&mut code));
+        assert!(code == [is_integer(5), Instr::Label(2), Instr::Label(3), Instr::Return]);
+    }
+
+    #[test]
+    fn unreachable() {
+        let mut code = vec![Instr::Return, Instr::Move{src: Operand::X(1), dst: Operand::X(0)}, Instr::Jump(2), Instr::Label(2), Instr::Return];
+        assert!(remove_unreachable(&mut code));
+        assert!(code == [Instr::Return, Instr::Label(2), Instr::Return]);
+    }
+}
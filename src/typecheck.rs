@@ -0,0 +1,1152 @@
+use diag::*;
+use std::collections::HashMap;
+use builtins::{self, Lowering};
+use parser::{Expr, ExprKind, Module, Func, FuncClause, BinopKind, Binop, Type, Pattern, PatternKind, MatchClause, CatchClause, MapPair, Segment, SegmentSpec, SegmentKind, After, Union};
+
+#[derive(Default)]
+struct Scope {
+    vars: Vec<(String, Type)>,
+}
+
+impl Scope {
+    fn lookup(&self, name: &str) -> Option<Type> {
+        self.vars
+            .iter()
+            .rev()
+            .find(|(var, _)| var == name)
+            .map(|(_, typ)| typ.clone())
+    }
+}
+
+// Whether the value of the type `actual` may be used where the type `expected` is.
+// Nothing is inferred here, so the type variables are compatible with anything.
+fn is_compatible_with(actual: &Type, expected: &Type) -> bool {
+    match (actual, expected) {
+        (Type::Any, _) | (_, Type::Any) => true,
+        (Type::Var(_), _) | (_, Type::Var(_)) => true,
+        // Records are just tagged tuples
+        (Type::Record(_), Type::Tuple(None)) => true,
+        (Type::Tuple(None), Type::Tuple(_)) | (Type::Tuple(_), Type::Tuple(None)) => true,
+        (Type::Tuple(Some(actual)), Type::Tuple(Some(expected))) => {
+            actual.len() == expected.len() && actual.iter().zip(expected.iter()).all(|(a, b)| is_compatible_with(a, b))
+        }
+        (Type::List(None), Type::List(_)) | (Type::List(_), Type::List(None)) => true,
+        (Type::List(Some(actual)), Type::List(Some(expected))) => is_compatible_with(actual, expected),
+        (Type::Map(None), Type::Map(_)) | (Type::Map(_), Type::Map(None)) => true,
+        (Type::Map(Some(actual)), Type::Map(Some(expected))) => {
+            is_compatible_with(&actual.0, &expected.0) && is_compatible_with(&actual.1, &expected.1)
+        }
+        (Type::Fun(None), Type::Fun(_)) | (Type::Fun(_), Type::Fun(None)) => true,
+        (Type::Fun(Some((actual_params, actual_ret))), Type::Fun(Some((expected_params, expected_ret)))) => {
+            // The function gets called with the arguments of the expected types
+            actual_params.len() == expected_params.len()
+                && expected_params.iter().zip(actual_params.iter()).all(|(a, b)| is_compatible_with(a, b))
+                && is_compatible_with(actual_ret, expected_ret)
+        }
+        _ => actual == expected,
+    }
+}
+
+fn human_type_list(types: &[Type]) -> String {
+    let mut list = String::new();
+    for (i, typ) in types.iter().enumerate() {
+        if i == 0 {
+            list.push_str(&format!("{typ}"))
+        } else if i + 1 >= types.len() {
+            list.push_str(&format!(" or {typ}"))
+        } else {
+            list.push_str(&format!(", {typ}"))
+        }
+    }
+    list
+}
+
+// The type of the value produced by several branches of code, when they can't
+// be unified. The containers of the same kind join into the ones with less
+// known elements.
+fn join_types(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        _ if a == b => Some(a.clone()),
+        (Type::Any, _) | (_, Type::Any) => Some(Type::Any),
+        (Type::Tuple(Some(a)), Type::Tuple(Some(b))) if a.len() == b.len() => {
+            let elements: Option<Vec<Type>> = a.iter().zip(b.iter()).map(|(a, b)| join_types(a, b)).collect();
+            Some(Type::Tuple(elements))
+        }
+        (Type::Tuple(_), Type::Tuple(_)) => Some(Type::Tuple(None)),
+        (Type::List(Some(a)), Type::List(Some(b))) => Some(Type::List(join_types(a, b).map(Box::new))),
+        (Type::List(_), Type::List(_)) => Some(Type::List(None)),
+        (Type::Map(Some(a)), Type::Map(Some(b))) => {
+            let pair = join_types(&a.0, &b.0).zip(join_types(&a.1, &b.1));
+            Some(Type::Map(pair.map(Box::new)))
+        }
+        (Type::Map(_), Type::Map(_)) => Some(Type::Map(None)),
+        (Type::Fun(Some((a_params, a_ret))), Type::Fun(Some((b_params, b_ret)))) if a_params.len() == b_params.len() => {
+            let params: Option<Vec<Type>> = a_params.iter().zip(b_params.iter()).map(|(a, b)| join_types(a, b)).collect();
+            let ret = join_types(a_ret, b_ret);
+            Some(Type::Fun(params.zip(ret).map(|(params, ret)| (params, Box::new(ret)))))
+        }
+        (Type::Fun(_), Type::Fun(_)) => Some(Type::Fun(None)),
+        _ => None,
+    }
+}
+
+// Mixed arithmetic follows Erlang: int op int stays int, as soon as
+// a float is involved the result is float, and `/` always produces float.
+fn type_of_binop(Binop{kind, lhs, rhs}: &Binop, scope: &mut Scope, checker: &mut Checker) -> Option<Type> {
+    let lhs_type = type_of_expr(lhs, scope, checker)?;
+    let rhs_type = type_of_expr(rhs, scope, checker)?;
+    match kind {
+        BinopKind::Sum | BinopKind::Sub | BinopKind::Mul | BinopKind::Div => {
+            checker.expect_type(lhs, &lhs_type, &[Type::Int, Type::Float])?;
+            checker.expect_type(rhs, &rhs_type, &[Type::Int, Type::Float])?;
+            match (kind, &lhs_type, &rhs_type) {
+                (BinopKind::Div, _, _) => Some(Type::Float),
+                (_, Type::Int, Type::Int) => Some(Type::Int),
+                (_, Type::Any, _) | (_, _, Type::Any) => Some(Type::Any),
+                // The type variable takes the type of the other operand
+                (_, Type::Var(_), _) | (_, _, Type::Var(_)) => {
+                    checker.unify(&rhs_type, &lhs_type, &rhs.to_span());
+                    Some(checker.resolve(&lhs_type))
+                }
+                _ => Some(Type::Float),
+            }
+        }
+        BinopKind::And | BinopKind::Or => {
+            checker.expect_type(lhs, &lhs_type, &[Type::Bool])?;
+            checker.expect_type(rhs, &rhs_type, &[Type::Bool])?;
+            Some(Type::Bool)
+        }
+        BinopKind::Less | BinopKind::LessEq | BinopKind::Greater | BinopKind::GreaterEq => {
+            checker.expect_type(lhs, &lhs_type, &[Type::Int, Type::Float])?;
+            checker.expect_type(rhs, &rhs_type, &[Type::Int, Type::Float])?;
+            Some(Type::Bool)
+        }
+        BinopKind::Eq | BinopKind::NotEq => {
+            match (&lhs_type, &rhs_type) {
+                (Type::Int | Type::Float, Type::Int | Type::Float) => {}
+                _ => checker.expect_type(rhs, &rhs_type, std::slice::from_ref(&lhs_type))?,
+            }
+            Some(Type::Bool)
+        }
+    }
+}
+
+fn type_of_send(pid: &Expr, msg: &Expr, scope: &mut Scope, checker: &mut Checker) -> Option<Type> {
+    let pid_type = type_of_expr(pid, scope, checker)?;
+    // Messages can be also sent to the registered names
+    checker.expect_type(pid, &pid_type, &[Type::Pid, Type::Atom])?;
+    type_of_expr(msg, scope, checker)
+}
+
+// The type of the values of the segment
+fn segment_type(spec: &SegmentSpec) -> Type {
+    match spec.kind {
+        SegmentKind::Integer => Type::Int,
+        SegmentKind::Float => Type::Float,
+        SegmentKind::Binary | SegmentKind::Bits => Type::Binary,
+    }
+}
+
+// Binds the variables of the pattern that matches a value of the type `typ`.
+// Matching can't fix the type variables, because the other clauses may match
+// the values of other types.
+fn bind_pattern(pattern: &Pattern, typ: &Type, scope: &mut Scope, checker: &mut Checker) -> Option<()> {
+    let typ = &checker.resolve(typ);
+    let pattern_type = match &pattern.kind {
+        PatternKind::Wildcard => return Some(()),
+        PatternKind::Var(name) => {
+            // Already bound variable in a pattern is compared with the value
+            match scope.lookup(&name.text) {
+                Some(existing_type) => existing_type,
+                None => {
+                    scope.vars.push((name.text.clone(), typ.clone()));
+                    return Some(())
+                }
+            }
+        }
+        PatternKind::Number(_) => Type::Int,
+        PatternKind::Bool(_) => Type::Bool,
+        PatternKind::Atom(_) => Type::Atom,
+        PatternKind::Tuple(elements) => {
+            for (index, element) in elements.iter().enumerate() {
+                let element_type = match typ {
+                    Type::Tuple(Some(types)) if types.len() == elements.len() => &types[index],
+                    _ => &Type::Any,
+                };
+                bind_pattern(element, element_type, scope, checker)?;
+            }
+            Type::Tuple(Some(vec![Type::Any; elements.len()]))
+        }
+        PatternKind::Map(pairs) => {
+            let (key_type, value_type) = match typ {
+                Type::Map(Some(pair)) => (&pair.0, &pair.1),
+                _ => (&Type::Any, &Type::Any),
+            };
+            for (key, value) in pairs.iter() {
+                bind_pattern(key, key_type, scope, checker)?;
+                bind_pattern(value, value_type, scope, checker)?;
+            }
+            Type::Map(None)
+        }
+        PatternKind::Binary(segments) => {
+            for segment in segments.iter() {
+                bind_pattern(&segment.value, &segment_type(&segment.spec), scope, checker)?;
+            }
+            Type::Binary
+        }
+        PatternKind::List{elements, tail} => {
+            let (element_type, list_type) = match typ {
+                Type::List(Some(element)) => (&**element, typ),
+                _ => (&Type::Any, &Type::List(None)),
+            };
+            for element in elements.iter() {
+                bind_pattern(element, element_type, scope, checker)?;
+            }
+            if let Some(tail) = tail {
+                bind_pattern(tail, list_type, scope, checker)?;
+            }
+            Type::List(None)
+        }
+        PatternKind::Constructor{name, args} => {
+            let module = checker.module;
+            let (union, ctor) = match module.find_constructor(&name.text) {
+                Some(found) => found,
+                None => {
+                    report!(&name, "ERROR", E0007, "Unknown constructor {name}", name = name.text);
+                    return None
+                }
+            };
+            if ctor.fields.len() != args.len() {
+                report!(&pattern, "ERROR", E0037, "Constructor {name} expects {expected} arguments, but got {actual}",
+                        name = name.text, expected = ctor.fields.len(), actual = args.len());
+                label!(&ctor.name, "The constructor is defined here");
+                return None
+            }
+            for (arg, field) in args.iter().zip(ctor.fields.iter()) {
+                bind_pattern(arg, field, scope, checker)?;
+            }
+            Type::Union(union.name.text.clone())
+        }
+    };
+
+    if !is_compatible_with(typ, &pattern_type) {
+        report!(&pattern, "ERROR", E0020, "Pattern of type {pattern_type} can never match a value of type {typ}");
+        return None
+    }
+    Some(())
+}
+
+// Whether the pattern matches any value of the type
+fn is_irrefutable(pattern: &Pattern, typ: &Type, scope: &Scope) -> bool {
+    match (&pattern.kind, typ) {
+        (PatternKind::Wildcard, _) => true,
+        // Already bound variable is compared with the value
+        (PatternKind::Var(name), _) => scope.lookup(&name.text).is_none(),
+        (PatternKind::Tuple(elements), Type::Tuple(Some(types))) => {
+            elements.len() == types.len() && elements.iter().zip(types.iter()).all(|(element, typ)| is_irrefutable(element, typ, scope))
+        }
+        _ => false,
+    }
+}
+
+// The case over a value of a union type should handle all its constructors.
+// The missing ones are only warned about, since the case just crashes on them.
+fn check_exhaustive(expr: &Expr, clauses: &[MatchClause], union: &Union, scope: &Scope) {
+    let mut missing = Vec::new();
+    for ctor in union.constructors.iter() {
+        let covered = clauses.iter().filter(|clause| clause.guard.is_none()).any(|MatchClause{pattern, ..}| match &pattern.kind {
+            PatternKind::Constructor{name, args} => {
+                name.text == ctor.name.text && args.iter().zip(ctor.fields.iter()).all(|(arg, typ)| is_irrefutable(arg, typ, scope))
+            }
+            _ => is_irrefutable(pattern, &Type::Union(union.name.text.clone()), scope),
+        });
+        if !covered {
+            missing.push(ctor.name.text.as_str());
+        }
+    }
+    if !missing.is_empty() {
+        report!(&expr, "WARNING", W0002, "Case does not handle the constructors {missing} of type {name}",
+                missing = missing.join(", "), name = union.name.text);
+        label!(&union.name, "The type is defined here");
+    }
+}
+
+// Type checks the clauses matching a value of the type `typ` and returns the type of their result
+fn type_of_clauses(clauses: &[MatchClause], typ: &Type, scope: &mut Scope, checker: &mut Checker) -> Option<Option<Type>> {
+    let mut ok = true;
+    let mut result_type: Option<(Type, &Expr)> = None;
+    for MatchClause{pattern, guard, body} in clauses.iter() {
+        let scope_len = scope.vars.len();
+        if bind_pattern(pattern, typ, scope, checker).is_none() {
+            ok = false;
+            continue;
+        }
+        if let Some(guard) = guard {
+            if check_guard(guard, scope, checker).is_none() {
+                ok = false;
+            }
+        }
+        match type_of_expr(body, scope, checker) {
+            Some(body_type) => match &result_type {
+                Some((expected, first_body)) => match checker.join(expected, &body_type, &body.to_span()) {
+                    Some(joined) => result_type = Some((joined, *first_body)),
+                    None => {
+                        report!(&body, "ERROR", E0019, "Clause returns {body_type}, but the previous clauses return {expected}");
+                        label!(&first_body, "The first clause returns {expected} here");
+                        ok = false;
+                    }
+                },
+                None => result_type = Some((body_type, body)),
+            }
+            None => ok = false,
+        }
+        scope.vars.truncate(scope_len);
+    }
+    if ok { Some(result_type.map(|(typ, _)| typ)) } else { None }
+}
+
+fn type_of_map_pairs(pairs: &[MapPair], scope: &mut Scope, checker: &mut Checker) -> Option<(Vec<Type>, Vec<Type>)> {
+    let mut keys = Vec::new();
+    let mut values = Vec::new();
+    for MapPair{key, value, ..} in pairs.iter() {
+        keys.push(type_of_expr(key, scope, checker)?);
+        values.push(type_of_expr(value, scope, checker)?);
+    }
+    Some((keys, values))
+}
+
+// The type variables that are already inferred are resolved in the result
+fn type_of_expr(expr: &Expr, scope: &mut Scope, checker: &mut Checker) -> Option<Type> {
+    let typ = type_of_unresolved_expr(expr, scope, checker)?;
+    Some(checker.resolve(&typ))
+}
+
+fn type_of_unresolved_expr(expr: &Expr, scope: &mut Scope, checker: &mut Checker) -> Option<Type> {
+    match &expr.kind {
+        ExprKind::Number(_) => Some(Type::Int),
+        ExprKind::Float(_) => Some(Type::Float),
+        ExprKind::Bool(_) => Some(Type::Bool),
+        ExprKind::Atom(_) => Some(Type::Atom),
+        ExprKind::Var(name) => {
+            match scope.lookup(&name.text) {
+                Some(typ) => Some(typ),
+                None if checker.module.consts.contains_key(&name.text) => checker.type_of_const(&name.text),
+                // The name of a function of the module is a fun referring to it
+                None => match checker.module.funcs.get(&name.text) {
+                    Some(func) => checker.type_of_func(func),
+                    None => {
+                        report!(&expr, "ERROR", E0003, "Unknown variable {name}", name = name.text);
+                        None
+                    }
+                },
+            }
+        }
+        ExprKind::Tuple(elements) => {
+            let mut types = Vec::new();
+            for element in elements.iter() {
+                types.push(type_of_expr(element, scope, checker)?);
+            }
+            Some(Type::Tuple(Some(types)))
+        }
+        ExprKind::Binop(binop) => type_of_binop(binop, scope, checker),
+        ExprKind::Not(operand) => {
+            let typ = type_of_expr(operand, scope, checker)?;
+            checker.expect_type(operand, &typ, &[Type::Bool])?;
+            Some(Type::Bool)
+        }
+        ExprKind::Send{pid, msg} => type_of_send(pid, msg, scope, checker),
+        ExprKind::Call{name, args} => {
+            // The constructors shadow the built-in functions, like error/1
+            let module = checker.module;
+            if let Some((union, ctor)) = module.find_constructor(&name.text) {
+                if ctor.fields.len() != args.len() {
+                    report!(&expr, "ERROR", E0037, "Constructor {name} expects {expected} arguments, but got {actual}",
+                            name = name.text, expected = ctor.fields.len(), actual = args.len());
+                    label!(&ctor.name, "The constructor is defined here");
+                    return None
+                }
+                for (arg, field) in args.iter().zip(ctor.fields.iter()) {
+                    let typ = type_of_expr(arg, scope, checker)?;
+                    checker.expect_type(arg, &typ, std::slice::from_ref(field))?;
+                }
+                return Some(Type::Union(union.name.text.clone()))
+            }
+
+            if let Some(builtin) = builtins::find(&name.text, args.len()) {
+                if let Lowering::Send = builtin.lowering {
+                    return type_of_send(&args[0], &args[1], scope, checker)
+                }
+                for (arg, expected) in args.iter().zip(builtin.params.iter()) {
+                    let typ = type_of_expr(arg, scope, checker)?;
+                    checker.expect_type(arg, &typ, expected)?;
+                }
+                return Some(builtin.ret.clone())
+            }
+
+            // The variables holding funs shadow the functions of the module
+            if let Some(typ) = scope.lookup(&name.text) {
+                let typ = checker.resolve(&typ);
+                let (params, ret) = match &typ {
+                    Type::Fun(Some((params, ret))) => {
+                        if params.len() != args.len() {
+                            report!(&expr, "ERROR", E0038, "Fun {name} of type {typ} expects {expected} arguments, but got {actual}",
+                                    name = name.text, expected = params.len(), actual = args.len());
+                            return None
+                        }
+                        (Some(params.clone()), (**ret).clone())
+                    }
+                    Type::Fun(None) | Type::Any => (None, Type::Any),
+                    // The fun is inferred from the call
+                    Type::Var(_) => {
+                        let params: Vec<Type> = args.iter().map(|_| checker.new_var()).collect();
+                        let ret = checker.new_var();
+                        checker.unify(&typ, &Type::Fun(Some((params.clone(), Box::new(ret.clone())))), &expr.to_span());
+                        (Some(params), ret)
+                    }
+                    _ => {
+                        report!(&name, "ERROR", E0022, "Variable {name} of type {typ} can't be called", name = name.text);
+                        return None
+                    }
+                };
+                for (index, arg) in args.iter().enumerate() {
+                    let arg_type = type_of_expr(arg, scope, checker)?;
+                    if let Some(params) = &params {
+                        checker.expect_type(arg, &arg_type, std::slice::from_ref(&params[index]))?;
+                    }
+                }
+                return Some(ret)
+            }
+
+            let func = match checker.module.funcs.get(&name.text) {
+                Some(func) => func,
+                None => {
+                    report!(&expr, "ERROR", E0004, "Unknown function {name}/{arity}", name = name.text, arity = args.len());
+                    return None
+                }
+            };
+            if func.arity() != args.len() {
+                report!(&expr, "ERROR", E0017, "Function {name} expects {expected} arguments, but got {actual}",
+                        name = name.text, expected = func.arity(), actual = args.len());
+                label!(&func.name, "The function is defined here");
+                return None
+            }
+            let (params, ret) = match checker.type_of_func(func)? {
+                Type::Fun(Some((params, ret))) => (params, ret),
+                _ => unreachable!("functions have function types"),
+            };
+            for (index, arg) in args.iter().enumerate() {
+                // Each clause may accept a different type
+                let mut expected: Vec<Type> = Vec::new();
+                for clause in func.clauses.iter() {
+                    let param = &clause.params[index];
+                    if !expected.contains(&param.typ) {
+                        expected.push(param.typ.clone());
+                    }
+                }
+                if expected.len() == 1 {
+                    expected = vec![params[index].clone()];
+                }
+                let typ = type_of_expr(arg, scope, checker)?;
+                checker.expect_type(arg, &typ, &expected)?;
+            }
+            Some(*ret)
+        }
+        ExprKind::Case{subject, clauses} => {
+            let subject_type = type_of_expr(subject, scope, checker)?;
+            let result_type = type_of_clauses(clauses, &subject_type, scope, checker)?;
+            if let Type::Union(name) = &subject_type {
+                check_exhaustive(expr, clauses, &checker.module.unions[name], scope);
+            }
+            Some(result_type.expect("case has at least one clause"))
+        }
+        ExprKind::Receive{clauses, after} => {
+            // Anybody can send us anything
+            let mut result_type = type_of_clauses(clauses, &Type::Any, scope, checker)?;
+            if let Some(After{timeout, body}) = after {
+                let timeout_type = type_of_expr(timeout, scope, checker)?;
+                checker.expect_type(timeout, &timeout_type, &[Type::Int])?;
+                let body_type = type_of_expr(body, scope, checker)?;
+                result_type = match &result_type {
+                    Some(expected) => match checker.join(expected, &body_type, &body.to_span()) {
+                        Some(joined) => Some(joined),
+                        None => {
+                            report!(&**body, "ERROR", E0019, "The after clause returns {body_type}, but the receive clauses return {expected}");
+                            return None
+                        }
+                    },
+                    None => Some(body_type),
+                };
+            }
+            Some(result_type.expect("receive has at least one clause or after"))
+        }
+        ExprKind::Map{base, pairs} => {
+            // Maps may hold anything as keys and values, but if they all are
+            // of the same types, the map is typed
+            let mut keys = Vec::new();
+            let mut values = Vec::new();
+            if let Some(base) = base {
+                let base_type = type_of_expr(base, scope, checker)?;
+                // The type variable becomes the map of something yet unknown
+                let map_type = match base_type {
+                    Type::Var(_) => Type::Map(Some(Box::new((checker.new_var(), checker.new_var())))),
+                    _ => Type::Map(None),
+                };
+                checker.expect_type(base, &base_type, &[map_type])?;
+                match checker.resolve(&base_type) {
+                    Type::Map(Some(pair)) => {
+                        keys.push(pair.0);
+                        values.push(pair.1);
+                    }
+                    _ => return type_of_map_pairs(pairs, scope, checker).map(|_| Type::Map(None)),
+                }
+            }
+            let (pair_keys, pair_values) = type_of_map_pairs(pairs, scope, checker)?;
+            keys.extend(pair_keys);
+            values.extend(pair_values);
+            let pair = checker.join_all(&keys, &expr.to_span()).zip(checker.join_all(&values, &expr.to_span()));
+            Some(Type::Map(pair.map(Box::new)))
+        }
+        ExprKind::Binary(segments) => {
+            for Segment{value, size, spec, ..} in segments.iter() {
+                let value_type = type_of_expr(value, scope, checker)?;
+                match segment_type(spec) {
+                    // Integers are converted to floats
+                    Type::Float => checker.expect_type(value, &value_type, &[Type::Int, Type::Float])?,
+                    typ => checker.expect_type(value, &value_type, &[typ])?,
+                }
+                if let Some(size) = size {
+                    let size_type = type_of_expr(size, scope, checker)?;
+                    checker.expect_type(size, &size_type, &[Type::Int])?;
+                }
+            }
+            Some(Type::Binary)
+        }
+        ExprKind::Record{name, base, fields} => {
+            let record = match checker.module.records.get(&name.text) {
+                Some(record) => record,
+                None => {
+                    report!(&name, "ERROR", E0006, "Unknown record {name}", name = name.text);
+                    return None
+                }
+            };
+            let record_type = Type::Record(name.text.clone());
+            if let Some(base) = base {
+                let base_type = type_of_expr(base, scope, checker)?;
+                checker.expect_type(base, &base_type, std::slice::from_ref(&record_type))?;
+            }
+            for (i, (field, value)) in fields.iter().enumerate() {
+                let index = match record.field_index(&field.text) {
+                    Some(index) => index,
+                    None => {
+                        report!(&field, "ERROR", E0008, "Record {name} has no field {field}", name = name.text, field = field.text);
+                        label!(&record.name, "The record is defined here");
+                        return None
+                    }
+                };
+                if let Some((existing, _)) = fields[..i].iter().find(|(existing, _)| existing.text == field.text) {
+                    report!(&field, "ERROR", E0023, "Field {field} is set more than once", field = field.text);
+                    label!(&existing, "The field is first set here");
+                    return None
+                }
+                let value_type = type_of_expr(value, scope, checker)?;
+                checker.expect_type(value, &value_type, &[record.fields[index].typ.clone()])?;
+            }
+            // A new record must have all its fields set
+            if base.is_none() {
+                for field in record.fields.iter() {
+                    if !fields.iter().any(|(name, _)| name.text == field.name.text) {
+                        report!(&expr, "ERROR", E0024, "Missing field {field} of record {name}", field = field.name.text, name = name.text);
+                        return None
+                    }
+                }
+            }
+            Some(record_type)
+        }
+        ExprKind::Field{record, field, record_name} => {
+            let mut record_type = type_of_expr(record, scope, checker)?;
+            // The record is inferred from the field, if only one of them has it
+            if let Type::Var(_) = record_type {
+                let mut candidates = checker.module.records.values().filter(|decl| decl.field_index(&field.text).is_some());
+                if let (Some(decl), None) = (candidates.next(), candidates.next()) {
+                    let typ = Type::Record(decl.name.text.clone());
+                    checker.unify(&record_type, &typ, &record.to_span());
+                    record_type = typ;
+                }
+            }
+            let name = match &record_type {
+                Type::Record(name) => name,
+                _ => {
+                    report!(&**record, "ERROR", E0018, "Expected a record, but got {record_type}");
+                    return None
+                }
+            };
+            let decl = &checker.module.records[name];
+            match decl.field_index(&field.text) {
+                Some(index) => {
+                    *record_name.borrow_mut() = Some(name.clone());
+                    Some(decl.fields[index].typ.clone())
+                }
+                None => {
+                    report!(&field, "ERROR", E0008, "Record {name} has no field {field}", field = field.text);
+                    label!(&decl.name, "The record is defined here");
+                    None
+                }
+            }
+        }
+        ExprKind::Try{body, clauses, catches} => {
+            let body_type = type_of_expr(body, scope, checker)?;
+            let mut result_type = if clauses.is_empty() {
+                body_type
+            } else {
+                type_of_clauses(clauses, &body_type, scope, checker)?.expect("of has at least one clause")
+            };
+            for CatchClause{class, clause} in catches.iter() {
+                let scope_len = scope.vars.len();
+                bind_pattern(class, &Type::Atom, scope, checker)?;
+                // Anything can be thrown
+                let catch_type = type_of_clauses(std::slice::from_ref(clause), &Type::Any, scope, checker)?.expect("one clause");
+                scope.vars.truncate(scope_len);
+                result_type = match checker.join(&result_type, &catch_type, &clause.body.to_span()) {
+                    Some(joined) => joined,
+                    None => {
+                        report!(&clause.body, "ERROR", E0019, "Catch clause returns {catch_type}, but the protected code returns {result_type}");
+                        return None
+                    }
+                };
+            }
+            Some(result_type)
+        }
+        ExprKind::List{elements, tail} => {
+            // The empty list fits any typed list
+            if elements.is_empty() && tail.is_none() {
+                return Some(Type::List(Some(Box::new(checker.new_var()))))
+            }
+            // Lists may hold anything, but if all the elements are of the
+            // same type, the list is typed
+            let mut types = Vec::new();
+            for element in elements.iter() {
+                types.push(type_of_expr(element, scope, checker)?);
+            }
+            if let Some(tail) = tail {
+                let tail_type = type_of_expr(tail, scope, checker)?;
+                // The type variable becomes the list of something yet unknown
+                let list_type = match tail_type {
+                    Type::Var(_) => Type::List(Some(Box::new(checker.new_var()))),
+                    _ => Type::List(None),
+                };
+                checker.expect_type(tail, &tail_type, &[list_type])?;
+                match checker.resolve(&tail_type) {
+                    Type::List(Some(element)) => types.push(*element),
+                    _ => return Some(Type::List(None)),
+                }
+            }
+            Some(Type::List(checker.join_all(&types, &expr.to_span()).map(Box::new)))
+        }
+        ExprKind::Comprehension{..} => unreachable!("comprehensions are desugared before type checking"),
+    }
+}
+
+// Guards are executed by the test instructions of BEAM, so only the
+// expressions that can't have side effects are allowed there.
+fn check_guard_safe(expr: &Expr, scope: &Scope, module: &Module) -> Option<()> {
+    match &expr.kind {
+        // The name of a function is a fun, and making it needs the stack
+        // frame, which is not there yet when the guard is run
+        ExprKind::Var(name) if scope.lookup(&name.text).is_none() && !module.consts.contains_key(&name.text) && module.funcs.contains_key(&name.text) => {
+            report!(&expr, "ERROR", E0021, "Function {name} can't be used as a value in guards", name = name.text);
+            None
+        }
+        ExprKind::Number(_) | ExprKind::Float(_) | ExprKind::Bool(_) | ExprKind::Atom(_) | ExprKind::Var(_) => Some(()),
+        ExprKind::Tuple(elements) => {
+            for element in elements.iter() {
+                check_guard_safe(element, scope, module)?;
+            }
+            Some(())
+        }
+        ExprKind::Binop(Binop{lhs, rhs, ..}) => {
+            check_guard_safe(lhs, scope, module)?;
+            check_guard_safe(rhs, scope, module)
+        }
+        ExprKind::Not(operand) => check_guard_safe(operand, scope, module),
+        ExprKind::Record{base, fields, ..} => {
+            if let Some(base) = base {
+                check_guard_safe(base, scope, module)?;
+            }
+            for (_, value) in fields.iter() {
+                check_guard_safe(value, scope, module)?;
+            }
+            Some(())
+        }
+        ExprKind::Field{record, ..} => check_guard_safe(record, scope, module),
+        ExprKind::Binary(segments) => {
+            for Segment{value, size, ..} in segments.iter() {
+                check_guard_safe(value, scope, module)?;
+                if let Some(size) = size {
+                    check_guard_safe(size, scope, module)?;
+                }
+            }
+            Some(())
+        }
+        ExprKind::Map{base, pairs} => {
+            if let Some(base) = base {
+                check_guard_safe(base, scope, module)?;
+            }
+            for MapPair{key, value, ..} in pairs.iter() {
+                check_guard_safe(key, scope, module)?;
+                check_guard_safe(value, scope, module)?;
+            }
+            Some(())
+        }
+        ExprKind::List{elements, tail} => {
+            for element in elements.iter() {
+                check_guard_safe(element, scope, module)?;
+            }
+            if let Some(tail) = tail {
+                check_guard_safe(tail, scope, module)?;
+            }
+            Some(())
+        }
+        ExprKind::Comprehension{..} => {
+            report!(&expr, "ERROR", E0021, "List comprehension is not allowed in guards");
+            None
+        }
+        ExprKind::Call{name, args} => match builtins::find(&name.text, args.len()) {
+            Some(builtin) if builtin.is_guard_safe() => {
+                for arg in args.iter() {
+                    check_guard_safe(arg, scope, module)?;
+                }
+                Some(())
+            }
+            _ => {
+                report!(&expr, "ERROR", E0021, "Call of {name} is not allowed in guards", name = name.text);
+                None
+            }
+        },
+        ExprKind::Send{..} => {
+            report!(&expr, "ERROR", E0021, "Sending messages is not allowed in guards");
+            None
+        }
+        ExprKind::Case{..} => {
+            report!(&expr, "ERROR", E0021, "case is not allowed in guards");
+            None
+        }
+        ExprKind::Receive{..} => {
+            report!(&expr, "ERROR", E0021, "receive is not allowed in guards");
+            None
+        }
+        ExprKind::Try{..} => {
+            report!(&expr, "ERROR", E0021, "try is not allowed in guards");
+            None
+        }
+    }
+}
+
+fn check_guard(guard: &Expr, scope: &mut Scope, checker: &mut Checker) -> Option<()> {
+    check_guard_safe(guard, scope, checker.module)?;
+    let typ = type_of_expr(guard, scope, checker)?;
+    checker.expect_type(guard, &typ, &[Type::Bool])
+}
+
+enum FuncState {
+    // The type of the function that is being checked. The recursive calls
+    // share its type variables, so they infer the same types.
+    InProgress(Type),
+    // The type variables of the `generic` list are replaced with the new
+    // ones on each use of the function
+    Checked {typ: Type, generic: Vec<String>},
+    Failed,
+}
+
+enum ConstState {
+    InProgress,
+    Checked(Type),
+    Failed,
+}
+
+// The return types of the functions are not annotated, so they are inferred
+// from the bodies in the order the functions are called. The types of the
+// functions that are not fixed by the annotations or the bodies stay generic,
+// Hindley-Milner style.
+struct Checker<'a> {
+    module: &'a Module,
+    funcs: HashMap<String, FuncState>,
+    consts: HashMap<String, ConstState>,
+    // The types the type variables are bound to and where it was inferred
+    bindings: HashMap<String, (Type, Span)>,
+    var_count: usize,
+}
+
+impl<'a> Checker<'a> {
+    fn new_var(&mut self) -> Type {
+        self.var_count += 1;
+        Type::Var(self.var_count.to_string())
+    }
+
+    // Replaces the bound type variables with their types
+    fn resolve(&self, typ: &Type) -> Type {
+        typ.map_vars(&mut |name| match self.bindings.get(name) {
+            Some((typ, _)) => self.resolve(typ),
+            None => Type::Var(name.to_string()),
+        })
+    }
+
+    // Where the type variable got the type it is bound to
+    fn origin(&self, typ: &Type) -> Option<&Span> {
+        let mut origin = None;
+        let mut typ = typ;
+        while let Type::Var(name) = typ {
+            let (bound, span) = self.bindings.get(name)?;
+            origin = Some(span);
+            typ = bound;
+        }
+        origin
+    }
+
+    // Makes the value of the type `actual` usable where the type `expected`
+    // is, binding the type variables on the way. The expression at the `span`
+    // is the reason for the binding.
+    fn unify(&mut self, actual: &Type, expected: &Type, span: &Span) -> bool {
+        let actual = self.resolve(actual);
+        let expected = self.resolve(expected);
+        match (&actual, &expected) {
+            (Type::Var(a), Type::Var(b)) if a == b => true,
+            (Type::Var(name), typ) | (typ, Type::Var(name)) => {
+                let mut vars = Vec::new();
+                typ.vars(&mut vars);
+                // The type can't contain itself
+                if vars.contains(name) {
+                    return false
+                }
+                self.bindings.insert(name.clone(), (typ.clone(), span.clone()));
+                true
+            }
+            (Type::Tuple(Some(actual)), Type::Tuple(Some(expected))) => {
+                actual.len() == expected.len() && actual.iter().zip(expected.iter()).all(|(a, b)| self.unify(a, b, span))
+            }
+            (Type::List(Some(actual)), Type::List(Some(expected))) => self.unify(actual, expected, span),
+            (Type::Map(Some(actual)), Type::Map(Some(expected))) => {
+                self.unify(&actual.0, &expected.0, span) && self.unify(&actual.1, &expected.1, span)
+            }
+            (Type::Fun(Some((actual_params, actual_ret))), Type::Fun(Some((expected_params, expected_ret)))) => {
+                // The function gets called with the arguments of the expected types
+                actual_params.len() == expected_params.len()
+                    && expected_params.iter().zip(actual_params.iter()).all(|(a, b)| self.unify(a, b, span))
+                    && self.unify(actual_ret, expected_ret, span)
+            }
+            _ => is_compatible_with(&actual, &expected),
+        }
+    }
+
+    // Unifies the types only if that succeeds completely
+    fn try_unify(&mut self, actual: &Type, expected: &Type, span: &Span) -> bool {
+        let bindings = self.bindings.clone();
+        if self.unify(actual, expected, span) {
+            true
+        } else {
+            self.bindings = bindings;
+            false
+        }
+    }
+
+    // The value may be of one of the several types, like any number. Such
+    // checks can't infer anything, so they only take a single type into account.
+    fn expect_type(&mut self, expr: &Expr, actual: &Type, expected: &[Type]) -> Option<()> {
+        let ok = match expected {
+            [expected] => self.unify(actual, expected, &expr.to_span()),
+            _ => {
+                let actual = self.resolve(actual);
+                expected.iter().any(|expected| is_compatible_with(&actual, &self.resolve(expected)))
+            }
+        };
+        if ok {
+            return Some(())
+        }
+
+        let expected_list: Vec<Type> = expected.iter().map(|typ| self.resolve(typ)).collect();
+        report!(&expr, "ERROR", E0018, "Expected {expected_list}, but got {actual}",
+                expected_list = human_type_list(&expected_list), actual = self.resolve(actual));
+        // Either of the conflicting types may be inferred somewhere else
+        for typ in expected.iter().chain(std::iter::once(actual)) {
+            if let Some(origin) = self.origin(typ) {
+                label!(origin, "The type {typ} is inferred here", typ = self.resolve(typ));
+            }
+        }
+        None
+    }
+
+    // The type of the value produced by several branches of code
+    fn join(&mut self, a: &Type, b: &Type, span: &Span) -> Option<Type> {
+        let (a, b) = (self.resolve(a), self.resolve(b));
+        if a == Type::Any || b == Type::Any {
+            return Some(Type::Any)
+        }
+        if self.try_unify(&b, &a, span) {
+            Some(self.resolve(&a))
+        } else {
+            join_types(&a, &b)
+        }
+    }
+
+    // The type of all the values of the list, if they have one
+    fn join_all(&mut self, types: &[Type], span: &Span) -> Option<Type> {
+        let (first, rest) = types.split_first()?;
+        let mut joined = first.clone();
+        for typ in rest.iter() {
+            joined = self.join(&joined, typ, span)?;
+        }
+        Some(joined)
+    }
+
+    // The constants are typed once, on their first use
+    fn type_of_const(&mut self, name: &str) -> Option<Type> {
+        let module = self.module;
+        let decl = &module.consts[name];
+        match self.consts.get(name) {
+            Some(ConstState::Checked(typ)) => Some(typ.clone()),
+            Some(ConstState::Failed) => None,
+            Some(ConstState::InProgress) => {
+                report!(&decl.name, "ERROR", E0026, "Constant {name} refers to itself");
+                self.consts.insert(name.to_string(), ConstState::Failed);
+                None
+            }
+            None => {
+                self.consts.insert(name.to_string(), ConstState::InProgress);
+                let typ = type_of_expr(&decl.value, &mut Scope::default(), self);
+                // The cycle may be already reported
+                let state = match (&typ, self.consts.get(name)) {
+                    (Some(typ), Some(ConstState::InProgress)) => ConstState::Checked(self.resolve(typ)),
+                    _ => ConstState::Failed,
+                };
+                self.consts.insert(name.to_string(), state);
+                self.type_of_const(name)
+            }
+        }
+    }
+
+    // The type of the function of the module. The generic type variables
+    // are fresh for each use.
+    fn type_of_func(&mut self, func: &'a Func) -> Option<Type> {
+        match self.funcs.get(&func.name.text) {
+            Some(FuncState::InProgress(typ)) => Some(typ.clone()),
+            Some(FuncState::Checked{typ, generic}) => {
+                let (typ, generic) = (typ.clone(), generic.clone());
+                let fresh: Vec<(String, Type)> = generic.into_iter().map(|name| (name, self.new_var())).collect();
+                Some(typ.map_vars(&mut |name| match fresh.iter().find(|(generic, _)| generic == name) {
+                    Some((_, var)) => var.clone(),
+                    None => Type::Var(name.to_string()),
+                }))
+            }
+            // The errors are already reported
+            Some(FuncState::Failed) => None,
+            None => {
+                let result = check_func(func, self);
+                self.funcs.remove(&func.name.text);
+                let state = match &result {
+                    Some(typ) => {
+                        let typ = self.resolve(typ);
+                        // The variables shared with the functions that are still being
+                        // checked may be bound later, so they are not generic
+                        let mut shared = Vec::new();
+                        for state in self.funcs.values() {
+                            if let FuncState::InProgress(other) = state {
+                                self.resolve(other).vars(&mut shared);
+                            }
+                        }
+                        let mut generic = Vec::new();
+                        typ.vars(&mut generic);
+                        generic.retain(|name| !shared.contains(name));
+                        FuncState::Checked {typ, generic}
+                    }
+                    None => FuncState::Failed,
+                };
+                self.funcs.insert(func.name.text.clone(), state);
+                self.type_of_func(func)
+            }
+        }
+    }
+}
+
+// Infers the type of the function. The type variables of the annotations
+// are the same for all the clauses of the function.
+fn check_func<'a>(func: &'a Func, checker: &mut Checker<'a>) -> Option<Type> {
+    let Func{name, clauses, ..} = func;
+    let mut vars: HashMap<String, Type> = HashMap::new();
+    let mut clause_params: Vec<Vec<Type>> = Vec::new();
+    for clause in clauses.iter() {
+        clause_params.push(clause.params.iter().map(|param| param.typ.map_vars(&mut |name| {
+            vars.entry(name.to_string()).or_insert_with(|| checker.new_var()).clone()
+        })).collect());
+    }
+    // Each clause may accept a different type
+    let mut params = Vec::new();
+    for index in 0..func.arity() {
+        let types: Vec<Type> = clause_params.iter().map(|types| types[index].clone()).collect();
+        params.push(checker.join_all(&types, &name.to_span()).unwrap_or(Type::Any));
+    }
+    let ret = checker.new_var();
+    checker.funcs.insert(name.text.clone(), FuncState::InProgress(Type::Fun(Some((params.clone(), Box::new(ret.clone()))))));
+
+    let mut ok = true;
+    let mut return_type: Option<(Type, &Expr)> = None;
+    for (FuncClause{params, guard, body}, param_types) in clauses.iter().zip(clause_params.iter()) {
+        let mut scope = Scope::default();
+        for (param, typ) in params.iter().zip(param_types.iter()) {
+            scope.vars.push((param.name.text.clone(), typ.clone()));
+        }
+        for (param, typ) in params.iter().zip(param_types.iter()) {
+            if let Some(pattern) = &param.pattern {
+                if bind_pattern(pattern, typ, &mut scope, checker).is_none() {
+                    ok = false;
+                }
+            }
+        }
+
+        if let Some(guard) = guard {
+            if check_guard(guard, &mut scope, checker).is_none() {
+                ok = false;
+            }
+        }
+
+        match type_of_expr(body, &mut scope, checker) {
+            Some(typ) => match &return_type {
+                Some((expected, first_body)) => match checker.join(expected, &typ, &body.to_span()) {
+                    Some(joined) => return_type = Some((joined, *first_body)),
+                    None => {
+                        report!(&body, "ERROR", E0019, "Clause of function {name} returns {typ}, but the previous clauses return {expected}", name = name.text);
+                        label!(&first_body, "The first clause returns {expected} here");
+                        ok = false;
+                    }
+                },
+                None => return_type = Some((typ, body)),
+            }
+            None => ok = false,
+        }
+    }
+    if !ok {
+        return None
+    }
+
+    // The recursive calls may have already inferred the return type
+    let (typ, first_body) = return_type.expect("function has at least one clause");
+    if !checker.unify(&typ, &ret, &first_body.to_span()) {
+        report!(&first_body, "ERROR", E0019, "Function {name} returns {typ}, but its recursive calls expect {ret}",
+                name = name.text, ret = checker.resolve(&ret));
+        if let Some(origin) = checker.origin(&ret) {
+            label!(origin, "The return type is inferred here");
+        }
+        return None
+    }
+    Some(Type::Fun(Some((params, Box::new(ret)))))
+}
+
+// The names of the records are parsed as types, so here we check that they exist.
+// The names of the union types are already resolved by the parser.
+fn check_type_exists(module: &Module, typ: &Type, loc: &Loc) -> Option<()> {
+    match typ {
+        Type::Record(name) if !module.records.contains_key(name) => {
+            report!(loc, "ERROR", E0005, "Unknown type {name}");
+            None
+        }
+        Type::Tuple(Some(elements)) => {
+            for element in elements.iter() {
+                check_type_exists(module, element, loc)?;
+            }
+            Some(())
+        }
+        Type::Fun(Some((params, ret))) => {
+            for param in params.iter() {
+                check_type_exists(module, param, loc)?;
+            }
+            check_type_exists(module, ret, loc)
+        }
+        Type::Map(Some(pair)) => {
+            check_type_exists(module, &pair.0, loc)?;
+            check_type_exists(module, &pair.1, loc)
+        }
+        Type::List(Some(element)) => check_type_exists(module, element, loc),
+        _ => Some(()),
+    }
+}
+
+pub fn check_module(module: &Module) -> Option<()> {
+    let mut ok = true;
+    for (_, record) in module.records.iter() {
+        for field in record.fields.iter() {
+            if check_type_exists(module, &field.typ, &field.name.loc).is_none() {
+                ok = false;
+            }
+            let mut vars = Vec::new();
+            field.typ.vars(&mut vars);
+            if !vars.is_empty() {
+                report!(&field.name, "ERROR", E0025, "Fields of records can't have type variables");
+                ok = false;
+            }
+        }
+    }
+    for (name, union) in module.unions.iter() {
+        if let Some(record) = module.records.get(name) {
+            report!(&union.name, "ERROR", E0036, "Type {name} conflicts with the record");
+            label!(&record.name, "The record is defined here");
+            ok = false;
+        }
+        for ctor in union.constructors.iter() {
+            for field in ctor.fields.iter() {
+                if check_type_exists(module, field, &ctor.name.loc).is_none() {
+                    ok = false;
+                }
+                let mut vars = Vec::new();
+                field.vars(&mut vars);
+                if !vars.is_empty() {
+                    report!(&ctor.name, "ERROR", E0025, "Constructors can't have type variables");
+                    ok = false;
+                }
+            }
+        }
+    }
+    for (_, func) in module.funcs.iter() {
+        for clause in func.clauses.iter() {
+            for param in clause.params.iter() {
+                if check_type_exists(module, &param.typ, &param.name.loc).is_none() {
+                    ok = false;
+                }
+            }
+        }
+    }
+    if !ok {
+        return None
+    }
+
+    let mut checker = Checker {
+        module,
+        funcs: HashMap::new(),
+        consts: HashMap::new(),
+        bindings: HashMap::new(),
+        var_count: 0,
+    };
+    for (name, _) in module.consts.iter() {
+        if checker.type_of_const(name).is_none() {
+            ok = false;
+        }
+    }
+    for (_, func) in module.funcs.iter() {
+        if let Some(builtin) = builtins::find(&func.name.text, func.arity()) {
+            report!(&func.name, "ERROR", E0035, "Function {name}/{arity} conflicts with the built-in function",
+                    name = builtin.name, arity = builtin.arity());
+            ok = false;
+        }
+        if let Some(decl) = module.consts.get(&func.name.text) {
+            report!(&func.name, "ERROR", E0016, "Function {name} conflicts with the constant", name = func.name.text);
+            label!(&decl.name, "The constant is defined here");
+            ok = false;
+        }
+        if let Some((_, ctor)) = module.find_constructor(&func.name.text) {
+            report!(&func.name, "ERROR", E0034, "Function {name} conflicts with the constructor", name = func.name.text);
+            label!(&ctor.name, "The constructor is defined here");
+            ok = false;
+        }
+        if checker.type_of_func(func).is_none() {
+            ok = false;
+        }
+    }
+    if ok { Some(()) } else { None }
+}